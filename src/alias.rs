@@ -0,0 +1,217 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::multimodal;
+
+// Walker's alias table, draws from a discrete distribution in constant time.
+#[derive(Debug)]
+pub struct AliasTable {
+    prob: Vec<f32>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    pub fn new(weights: &[f32]) -> AliasTable {
+        let num_weights = weights.len();
+        let norm: f32 = weights.iter().sum();
+
+        let mut prob: Vec<f32> = weights
+            .iter()
+            .map(|x| x * num_weights as f32 / norm)
+            .collect();
+        let mut alias: Vec<usize> = (0..num_weights).collect();
+
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..num_weights).partition(|&index| prob[index] < 1.0);
+        while !small.is_empty() && !large.is_empty() {
+            let less = small.pop().unwrap();
+            let more = *large.last().unwrap();
+
+            alias[less] = more;
+            prob[more] -= 1.0 - prob[less];
+            if prob[more] < 1.0 {
+                large.pop();
+                small.push(more);
+            }
+        }
+
+        // leftovers are only off from one by rounding errors
+        for index in small.into_iter().chain(large) {
+            prob[index] = 1.0;
+        }
+
+        AliasTable { prob, alias }
+    }
+
+    // a single uniform value picks both the column and the coin of the column
+    pub fn sample(&self, coin_val: f32) -> usize {
+        let scaled = coin_val * self.prob.len() as f32;
+        let index = (scaled as usize).min(self.prob.len() - 1);
+        match scaled - (index as f32) < self.prob[index] {
+            true => index,
+            false => self.alias[index],
+        }
+    }
+}
+
+// Candidate features of one link neighbourhood, i.e. all the features linked to
+// a given feature of the other assay. The alias table of a row is only built on
+// its first draw, and rows with the very same weights share a single table.
+#[derive(Debug)]
+pub struct Neighbourhood {
+    features: Vec<usize>,
+    prior: Vec<f32>,
+    fallback: Option<AliasTable>,
+    rows: RefCell<HashMap<usize, Option<usize>>>,
+    tables: RefCell<Vec<AliasTable>>,
+    shared: RefCell<HashMap<Vec<u32>, usize>>,
+}
+
+impl Neighbourhood {
+    fn new(features: &[usize], prior: &[f32]) -> Neighbourhood {
+        let is_uniform = prior.iter().all(|&x| x == prior[0]);
+        let fallback = match is_uniform || prior.iter().sum::<f32>() == 0.0 {
+            true => None,
+            false => Some(AliasTable::new(prior)),
+        };
+
+        Neighbourhood {
+            features: features.to_vec(),
+            prior: prior.to_vec(),
+            fallback,
+            rows: RefCell::new(HashMap::new()),
+            tables: RefCell::new(Vec::new()),
+            shared: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // a zero prior can cancel all the signal of a row, leaving it without a table
+    fn table_index(&self, mat: &multimodal::SubMatrix, row: usize) -> Option<usize> {
+        let weights: Vec<f32> = self
+            .features
+            .iter()
+            .zip(self.prior.iter())
+            .map(|(&x, &weight)| mat.get(row, x) * weight)
+            .collect();
+        if weights.iter().sum::<f32>() <= 0.0 {
+            return None;
+        }
+
+        let key: Vec<u32> = weights.iter().map(|x| x.to_bits()).collect();
+        let mut tables = self.tables.borrow_mut();
+        let index = *self.shared.borrow_mut().entry(key).or_insert_with(|| {
+            tables.push(AliasTable::new(&weights));
+            tables.len() - 1
+        });
+        Some(index)
+    }
+
+    // rows without any signal fall back to the link priors, if any, or else
+    // to a uniform choice
+    pub fn sample(&self, mat: &multimodal::SubMatrix, row: usize, coin_val: f32) -> usize {
+        if self.features.len() == 1 {
+            return self.features[0];
+        }
+
+        let cached = self.rows.borrow().get(&row).cloned();
+        let table = match cached {
+            Some(table) => table,
+            None => {
+                let table = self.table_index(mat, row);
+                self.rows.borrow_mut().insert(row, table);
+                table
+            }
+        };
+        let index = match (table, &self.fallback) {
+            (Some(table), _) => self.tables.borrow()[table].sample(coin_val),
+            (None, Some(fallback)) => fallback.sample(coin_val),
+            (None, None) => {
+                let index = (coin_val * self.features.len() as f32) as usize;
+                index.min(self.features.len() - 1)
+            }
+        };
+
+        self.features[index]
+    }
+
+    pub fn _num_tables(&self) -> usize {
+        self.tables.borrow().len()
+    }
+}
+
+// neighbourhoods[k] samples among candidates[k], indexed by submatrix columns,
+// with the values of the candidates scaled by their priors[k]
+pub fn build_neighbourhoods(candidates: &[Vec<usize>], priors: &[Vec<f32>]) -> Vec<Neighbourhood> {
+    candidates
+        .iter()
+        .zip(priors.iter())
+        .map(|(features, prior)| Neighbourhood::new(features, prior))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alias;
+    use crate::fixtures;
+
+    #[test]
+    fn test_alias_table() {
+        let table = alias::AliasTable::new(&[1.0, 0.0, 3.0, 4.0]);
+
+        let num_coins = 80_000;
+        let mut counts = [0; 4];
+        for coin in 0..num_coins {
+            counts[table.sample(coin as f32 / num_coins as f32)] += 1;
+        }
+
+        let freqs: Vec<f32> = counts
+            .iter()
+            .map(|&x| x as f32 / num_coins as f32)
+            .collect();
+        let expected = [0.125, 0.0, 0.375, 0.5];
+        for (x, y) in freqs.iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_neighbourhoods() {
+        let mm_obj = fixtures::experiment();
+
+        // cell 1 only expresses feature 3, cell 3 only feature 1
+        let sub_mat = mm_obj.get_submatrix(&mm_obj.cell_index(1, None), &[0, 1, 3], 1);
+        let candidates = vec![vec![0, 2], vec![1]];
+        let hoods = alias::build_neighbourhoods(&candidates, &[vec![1.0, 1.0], vec![1.0]]);
+
+        assert_eq!(hoods[0].sample(&sub_mat, 1, 0.1), 2);
+        assert_eq!(hoods[0].sample(&sub_mat, 1, 0.9), 2);
+        assert_eq!(hoods[1].sample(&sub_mat, 1, 0.5), 1);
+        assert_eq!(hoods[0].sample(&sub_mat, 3, 0.1), 0);
+        assert_eq!(hoods[0].sample(&sub_mat, 3, 0.9), 2);
+        assert_eq!(hoods[0]._num_tables(), 1);
+
+        // a zero prior rules the expressed feature out, leaving only the prior
+        let hoods = alias::build_neighbourhoods(&candidates, &[vec![1.0, 0.0], vec![1.0]]);
+        assert_eq!(hoods[0].sample(&sub_mat, 1, 0.1), 0);
+        assert_eq!(hoods[0].sample(&sub_mat, 1, 0.9), 0);
+        assert_eq!(hoods[0].sample(&sub_mat, 3, 0.9), 0);
+        assert_eq!(hoods[0]._num_tables(), 0);
+    }
+
+    #[test]
+    fn test_shared_tables() {
+        // the first six cells express the first gene, the last six the second
+        let mm_obj = fixtures::paired("test/planted/sec", "test/planted/pivot");
+        let sub_mat = mm_obj.get_submatrix(&mm_obj.cell_index(1, None), &[0, 1], 1);
+        let hoods = alias::build_neighbourhoods(&[vec![0, 1]], &[vec![1.0, 1.0]]);
+
+        for row in 0..12 {
+            let expected = match row < 6 {
+                true => 0,
+                false => 1,
+            };
+            assert_eq!(hoods[0].sample(&sub_mat, row, 0.5), expected);
+        }
+        assert_eq!(hoods[0]._num_tables(), 2);
+    }
+}
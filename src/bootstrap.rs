@@ -0,0 +1,181 @@
+use std::error::Error;
+
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::configs;
+use crate::gibbs;
+use crate::links;
+use crate::multimodal;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    pub mean: f32,
+    pub sd: f32,
+    pub lo: f32,
+    pub hi: f32,
+}
+
+impl Interval {
+    // mean, standard deviation and percentile interval of the replicates
+    pub fn new(mut replicates: Vec<f32>, ci_level: f32) -> Interval {
+        let n = replicates.len() as f32;
+        let mean = replicates.iter().sum::<f32>() / n;
+        let sd = match replicates.len() > 1 {
+            true => (replicates.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / (n - 1.0)).sqrt(),
+            false => 0.0,
+        };
+
+        replicates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let alpha = (1.0 - ci_level) / 2.0;
+        let percentile = |q: f32| replicates[((n - 1.0) * q).round() as usize];
+
+        Interval {
+            mean,
+            sd,
+            lo: percentile(alpha),
+            hi: percentile(1.0 - alpha),
+        }
+    }
+}
+
+// Resamples the cells of a region with replacement and re-estimates the gamma
+// for every replicate, the replicates are summarized independently per link.
+pub fn process_region(
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+    config: &configs::GibbsConfig,
+    links_obj: &links::Links<f32>,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    cells: Option<&[usize]>,
+) -> Result<Vec<Interval>, Box<dyn Error>> {
+    let pool: Vec<usize> = match cells {
+        Some(cells) => cells.to_vec(),
+        None => (0..mm_obj.num_cells(links_obj.sec_assay())).collect(),
+    };
+    let pool_dist = Uniform::from(0..pool.len());
+
+    let seed = |replicate| {
+        gibbs::stream_seed(
+            config.seed,
+            "bootstrap",
+            replicate,
+            links_obj,
+            sec_feats,
+            pivot_feats,
+        )
+    };
+    let mut rng = StdRng::seed_from_u64(seed(0));
+
+    let num_states = sec_feats.len() * pivot_feats.len();
+    let mut replicates = vec![Vec::with_capacity(config.num_bootstraps); num_states];
+    for replicate in 0..config.num_bootstraps {
+        let resampled: Vec<usize> = (0..pool.len())
+            .map(|_| pool[pool_dist.sample(&mut rng)])
+            .collect();
+
+        let replicate_config = configs::GibbsConfig {
+            seed: seed(replicate + 1),
+            cell_attribution: false,
+            ..config.clone()
+        };
+        let gamma = gibbs::estimate_region(
+            sec_feats,
+            pivot_feats,
+            &replicate_config,
+            links_obj,
+            mm_obj,
+            Some(&resampled),
+            None,
+        )?;
+
+        for (index, val) in gamma.values().iter().enumerate() {
+            replicates[index].push(*val);
+        }
+    }
+
+    Ok(replicates
+        .into_iter()
+        .map(|x| Interval::new(x, config.ci_level))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bootstrap;
+    use crate::configs::GibbsConfig;
+    use crate::fixtures;
+    use crate::gibbs;
+
+    #[test]
+    fn test_interval() {
+        let replicates = (0..101).map(|x| x as f32 / 100.0).collect();
+        let interval = bootstrap::Interval::new(replicates, 0.9);
+
+        assert!((interval.mean - 0.5).abs() < 1e-6);
+        assert!((interval.lo - 0.05).abs() < 1e-6);
+        assert!((interval.hi - 0.95).abs() < 1e-6);
+        assert!(interval.sd > 0.29 && interval.sd < 0.3);
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        // the first two peaks and genes share the same halves of the cells
+        let mm_obj = fixtures::paired("test/planted/sec", "test/planted/pivot");
+        let links_obj = fixtures::links(&mm_obj, "test/planted/links.tsv");
+
+        let pivot_feats = vec![0, 1];
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let config = GibbsConfig {
+            exact_max_states: 4,
+            num_bootstraps: 20,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+        let estimate = |cells: Option<&[usize]>| {
+            gibbs::estimate_region(
+                &sec_feats,
+                &pivot_feats,
+                &config,
+                &links_obj,
+                &mm_obj,
+                cells,
+                None,
+            )
+            .unwrap()
+        };
+
+        let gamma = estimate(None);
+        let intervals =
+            bootstrap::process_region(&sec_feats, &pivot_feats, &config, &links_obj, &mm_obj, None)
+                .unwrap();
+        assert_eq!(intervals.len(), sec_feats.len() * pivot_feats.len());
+        for (interval, &val) in intervals.iter().zip(gamma.values().iter()) {
+            assert!(interval.lo <= val && val <= interval.hi);
+            assert!(interval.sd >= 0.0);
+        }
+        // no resampling of the cells links the peaks to the other half's gene
+        assert_eq!(intervals[1].sd, 0.0);
+        assert_eq!(intervals[2].sd, 0.0);
+        assert!(intervals[0].sd > 0.0);
+
+        // resampling copies of the same cell gives the same gamma every time
+        let cells = vec![3, 3, 3];
+        let gamma = estimate(Some(&cells));
+        let intervals = bootstrap::process_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            Some(&cells),
+        )
+        .unwrap();
+        for (interval, &val) in intervals.iter().zip(gamma.values().iter()) {
+            assert_eq!(interval.sd, 0.0);
+            assert!((interval.mean - val).abs() < 1e-6);
+            assert_eq!((interval.lo, interval.hi), (val, val));
+        }
+    }
+}
@@ -1 +1,113 @@
-pub const NUM_SAMPLES: usize = 1_000_000;
+use std::path::PathBuf;
+
+pub const MIN_SAMPLES: usize = 10_000;
+pub const MAX_SAMPLES: usize = 1_000_000;
+pub const SAMPLES_PER_STATE: usize = 10_000;
+pub const NUM_CHAINS: usize = 4;
+pub const NUM_BATCHES: usize = 50;
+pub const BURN_IN: usize = 10_000;
+pub const THIN: usize = 1;
+pub const RHAT_THRESHOLD: f32 = 1.1;
+pub const EXACT_MAX_STATES: usize = 64;
+pub const EXACT_MAX_ITERS: usize = 100_000;
+pub const EXACT_TOLERANCE: f64 = 1e-12;
+pub const CI_LEVEL: f32 = 0.95;
+pub const MAX_PATHS: usize = 100_000;
+pub const NUM_THREADS: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct GibbsConfig {
+    pub min_samples: usize,
+    pub max_samples: usize,
+    pub samples_per_state: usize,
+    pub target_mcse: Option<f32>,
+    pub num_chains: usize,
+    pub rhat_threshold: f32,
+    pub burn_in: usize,
+    pub thin: usize,
+    pub seed: u64,
+    pub exact_max_states: usize,
+    pub num_bootstraps: usize,
+    pub ci_level: f32,
+    pub num_permutations: usize,
+    pub permute_within_microclusters: bool,
+    pub num_threads: usize,
+    pub cell_attribution: bool,
+    pub max_paths: usize,
+}
+
+impl Default for GibbsConfig {
+    fn default() -> GibbsConfig {
+        GibbsConfig {
+            min_samples: MIN_SAMPLES,
+            max_samples: MAX_SAMPLES,
+            samples_per_state: SAMPLES_PER_STATE,
+            target_mcse: None,
+            num_chains: NUM_CHAINS,
+            rhat_threshold: RHAT_THRESHOLD,
+            burn_in: BURN_IN,
+            thin: THIN,
+            seed: rand::random::<u64>(),
+            exact_max_states: EXACT_MAX_STATES,
+            num_bootstraps: 0,
+            ci_level: CI_LEVEL,
+            num_permutations: 0,
+            permute_within_microclusters: false,
+            num_threads: NUM_THREADS,
+            cell_attribution: false,
+            max_paths: MAX_PATHS,
+        }
+    }
+}
+
+impl GibbsConfig {
+    // total number of samples, over all chains, a region starts with
+    pub fn sample_budget(&self, num_states: usize) -> usize {
+        match self.target_mcse {
+            Some(_) => self.min_samples,
+            None => (self.samples_per_state * num_states)
+                .max(self.min_samples)
+                .min(self.max_samples),
+        }
+    }
+
+    // regions are always sampled when the cells driving the chain are tracked
+    pub fn is_exact(&self, num_states: usize) -> bool {
+        num_states <= self.exact_max_states && !self.cell_attribution
+    }
+
+    // Rough number of steps estimate_region spends on a region. The exact
+    // solver is dominated by building its kernel, going over every linked
+    // pivot for every state of every cell, while the sampler runs the burn-in
+    // of every chain and then its budget, thinned. A target error is costed at
+    // the budget it starts from, the doublings it takes aren't known upfront.
+    pub fn region_cost(&self, num_secs: usize, num_pivots: usize, num_cells: usize) -> usize {
+        let num_states = num_secs * num_pivots;
+        match self.is_exact(num_states) {
+            true => num_cells * num_states * num_pivots,
+            false => {
+                self.num_chains.max(1) * self.burn_in
+                    + self.sample_budget(num_states) * self.thin.max(1)
+            }
+        }
+    }
+}
+
+// optional outputs written along with the gamma table
+#[derive(Debug, Clone, Default)]
+pub struct OutputConfig {
+    pub mtx_dir: Option<PathBuf>,
+    pub summary_dir: Option<PathBuf>,
+    pub attribution_dir: Option<PathBuf>,
+}
+
+impl OutputConfig {
+    // every microcluster gets its own subdirectories
+    pub fn with_subdir(&self, name: &str) -> OutputConfig {
+        OutputConfig {
+            mtx_dir: self.mtx_dir.as_ref().map(|dir| dir.join(name)),
+            summary_dir: self.summary_dir.as_ref().map(|dir| dir.join(name)),
+            attribution_dir: self.attribution_dir.as_ref().map(|dir| dir.join(name)),
+        }
+    }
+}
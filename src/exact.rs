@@ -0,0 +1,372 @@
+use std::error::Error;
+
+use crate::configs;
+use crate::gibbs;
+use crate::links;
+use crate::multimodal;
+
+// Every gibbs step draws a new state only based on the current pivot feature,
+// so the chain is fully described by a kernel from the pivot features to the
+// states, and the stationary distribution follows from the one of the
+// marginal chain over the pivot features.
+pub fn process_region(
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+    links_obj: &links::Links<f32>,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    cells: Option<&[usize]>,
+    permutation: Option<&[usize]>,
+) -> Result<gibbs::Gamma, Box<dyn Error>> {
+    let kernel = transition_kernel(
+        sec_feats,
+        pivot_feats,
+        links_obj,
+        mm_obj,
+        cells,
+        permutation,
+    );
+    let pivot_dist = stationary_distribution(&marginal_chain(&kernel, pivot_feats.len()));
+
+    let num_states = sec_feats.len() * pivot_feats.len();
+    let mut probs = vec![0.0_f64; num_states];
+    for (pivot, row) in kernel.iter().enumerate() {
+        for (index, val) in row.iter().enumerate() {
+            probs[index] += pivot_dist[pivot] * val;
+        }
+    }
+
+    let values = probs.into_iter().map(|x| x as f32).collect();
+    Ok(gibbs::Gamma::from_probabilities(
+        sec_feats.len(),
+        pivot_feats.len(),
+        values,
+    ))
+}
+
+// kernel[p][s * num_pivots + q] is the probability of moving into the state
+// (s, q) when the current pivot feature is p, averaged over cells and anchors.
+fn transition_kernel(
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+    links_obj: &links::Links<f32>,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    cells: Option<&[usize]>,
+    permutation: Option<&[usize]>,
+) -> Vec<Vec<f64>> {
+    let num_pivot_feats = pivot_feats.len();
+    let num_states = sec_feats.len() * num_pivot_feats;
+
+    // the sec matrix only holds the cells driving the chain, while the pivot
+    // matrix keeps all the cells the anchors or a permutation can jump to
+    let cell_index = mm_obj.cell_index(links_obj.sec_assay(), cells);
+    let pivot_mat = mm_obj.get_submatrix(
+        &mm_obj.cell_index(links_obj.pivot_assay(), None),
+        pivot_feats,
+        links_obj.pivot_assay(),
+    );
+    let sec_mat = mm_obj.get_submatrix(&cell_index, sec_feats, links_obj.sec_assay());
+    let num_cells = sec_mat.num_rows();
+
+    let (sec_indices, pivot_indices) = links_obj.local_links(sec_feats, pivot_feats);
+    let (sec_weights, pivot_weights) = links_obj.local_weights(sec_feats, pivot_feats);
+
+    let cell_prob = 1.0 / num_cells as f64;
+    let mut kernel = vec![vec![0.0_f64; num_states]; num_pivot_feats];
+    for (pivot, row) in kernel.iter_mut().enumerate() {
+        for cell_row in 0..num_cells {
+            let sec_probs = mm_obj.feature_probabilities(
+                &sec_mat,
+                &sec_indices[pivot],
+                &sec_weights[pivot],
+                cell_row,
+            );
+            let jumps = links_obj.jump_probabilities(cell_index.to_global(cell_row));
+
+            for (&sec, &sec_prob) in sec_indices[pivot].iter().zip(sec_probs.iter()) {
+                if sec_prob == 0.0 {
+                    continue;
+                }
+
+                for &(pivot_cell, jump_prob) in &jumps {
+                    let pivot_cell = permutation.map_or(pivot_cell, |perm| perm[pivot_cell]);
+                    let pivot_probs = mm_obj.feature_probabilities(
+                        &pivot_mat,
+                        &pivot_indices[sec],
+                        &pivot_weights[sec],
+                        pivot_cell,
+                    );
+
+                    let prob = cell_prob * sec_prob as f64 * jump_prob as f64;
+                    for (&next, &pivot_prob) in pivot_indices[sec].iter().zip(pivot_probs.iter()) {
+                        row[sec * num_pivot_feats + next] += prob * pivot_prob as f64;
+                    }
+                }
+            }
+        }
+    }
+
+    kernel
+}
+
+fn marginal_chain(kernel: &[Vec<f64>], num_pivot_feats: usize) -> Vec<Vec<f64>> {
+    kernel
+        .iter()
+        .map(|row| {
+            let mut marginal = vec![0.0_f64; num_pivot_feats];
+            for (index, val) in row.iter().enumerate() {
+                marginal[index % num_pivot_feats] += val;
+            }
+            marginal
+        })
+        .collect()
+}
+
+// Power iteration on the lazy version of the chain, started from the uniform
+// distribution the sampler draws its initial state from. Being lazy the chain
+// is aperiodic, while sharing the stationary distribution of the original.
+fn stationary_distribution(transitions: &[Vec<f64>]) -> Vec<f64> {
+    let num_states = transitions.len();
+    let mut dist = vec![1.0 / num_states as f64; num_states];
+
+    for _ in 0..configs::EXACT_MAX_ITERS {
+        let mut next: Vec<f64> = dist.iter().map(|x| 0.5 * x).collect();
+        for (from, row) in transitions.iter().enumerate() {
+            for (to, val) in row.iter().enumerate() {
+                next[to] += 0.5 * dist[from] * val;
+            }
+        }
+
+        let norm: f64 = next.iter().sum();
+        next.iter_mut().for_each(|x| *x /= norm);
+
+        let delta: f64 = next
+            .iter()
+            .zip(dist.iter())
+            .map(|(x, y)| (x - y).abs())
+            .sum();
+        dist = next;
+
+        if delta < configs::EXACT_TOLERANCE {
+            return dist;
+        }
+    }
+
+    warn!(
+        "Stationary distribution didn't converge in {} iterations",
+        configs::EXACT_MAX_ITERS
+    );
+    dist
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::configs::GibbsConfig;
+    use crate::exact;
+    use crate::fixtures;
+    use crate::gibbs;
+    use crate::links::{Links, Orientation, SkipReport};
+    use crate::multimodal::MultiModalExperiment;
+
+    #[test]
+    fn test_stationary_distribution() {
+        let transitions = vec![vec![0.0, 1.0], vec![0.5, 0.5]];
+        let dist = exact::stationary_distribution(&transitions);
+        assert!((dist[0] - 1.0 / 3.0).abs() < 1e-9);
+        assert!((dist[1] - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_exact() {
+        let mm_obj = fixtures::experiment();
+
+        let config = GibbsConfig {
+            min_samples: 200_000,
+            max_samples: 200_000,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+        let weighted_obj = fixtures::links(&mm_obj, "test/olaps_weighted.tsv");
+
+        for (links_obj, pivot_feats) in [
+            (&links_obj, vec![2]),
+            (&links_obj, vec![0, 1, 3]),
+            (&weighted_obj, vec![2]),
+            (&weighted_obj, vec![0, 1, 3]),
+        ] {
+            let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+            let exact =
+                exact::process_region(&sec_feats, &pivot_feats, links_obj, &mm_obj, None, None)
+                    .unwrap();
+            let sampled = gibbs::process_region(
+                &sec_feats,
+                &pivot_feats,
+                &config,
+                links_obj,
+                &mm_obj,
+                None,
+                None,
+            )
+            .unwrap();
+
+            let total: f32 = exact.values().iter().sum();
+            assert!((total - 1.0).abs() < 1e-4);
+
+            let is_close = exact
+                .values()
+                .iter()
+                .zip(sampled.values().iter())
+                .all(|(x, y)| (x - y).abs() < 1e-2);
+            assert!(is_close);
+
+            // the zero weighted link of the peak to FAM138A is never chosen
+            if links_obj.has_weights() && pivot_feats[0] == 0 {
+                assert_eq!(sec_feats[0], 0);
+                assert_eq!(exact.values()[0], 0.0);
+                assert_eq!(sampled.values()[0], 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_microcluster_anchors() {
+        let mm_obj = fixtures::experiment();
+
+        let mut report = SkipReport::default();
+        let opath = Path::new("test/olaps.tsv");
+        let mut links_obj = Links::new(
+            &mm_obj,
+            opath.to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut report,
+        )
+        .unwrap();
+        let mpath = Path::new("test/microclusters.tsv");
+        links_obj
+            .read_microclusters(mpath.to_path_buf(), &mut report)
+            .unwrap();
+        let apath = Path::new("test/anchors.tsv");
+        links_obj
+            .read_anchors(apath.to_path_buf(), &mut report)
+            .unwrap();
+
+        let config = GibbsConfig {
+            min_samples: 200_000,
+            max_samples: 200_000,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+
+        let pivot_feats = vec![0, 1, 3];
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let mut keys: Vec<&String> = links_obj.microcluster().unwrap().keys().collect();
+        keys.sort();
+        for key in keys {
+            let cells = &links_obj.microcluster().unwrap()[key];
+            let exact = exact::process_region(
+                &sec_feats,
+                &pivot_feats,
+                &links_obj,
+                &mm_obj,
+                Some(cells),
+                None,
+            )
+            .unwrap();
+            let sampled = gibbs::process_region(
+                &sec_feats,
+                &pivot_feats,
+                &config,
+                &links_obj,
+                &mm_obj,
+                Some(cells),
+                None,
+            )
+            .unwrap();
+
+            let is_close = exact
+                .values()
+                .iter()
+                .zip(sampled.values().iter())
+                .all(|(x, y)| (x - y).abs() < 1e-2);
+            assert!(is_close);
+        }
+    }
+
+    #[test]
+    fn test_unpaired() {
+        let spath = Path::new("test/sec");
+        let ppath = Path::new("test/pivot");
+        let upath = Path::new("test/pivot_unpaired");
+        let paired_obj =
+            MultiModalExperiment::from_paths(vec![spath.to_path_buf(), ppath.to_path_buf()]);
+        let unpaired_obj =
+            MultiModalExperiment::from_paths(vec![spath.to_path_buf(), upath.to_path_buf()]);
+        assert!(!unpaired_obj.is_paired(0, 1));
+        assert_eq!(unpaired_obj.num_cells(1), 3);
+
+        // the unpaired pivot holds the rows of the pivot cells 2, 0 & 4 and the
+        // anchors link every sec cell to its own row
+        let mut report = SkipReport::default();
+        let opath = Path::new("test/olaps.tsv");
+        let paired_links = Links::new(
+            &paired_obj,
+            opath.to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut report,
+        )
+        .unwrap();
+        let mut unpaired_links = Links::new(
+            &unpaired_obj,
+            opath.to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut report,
+        )
+        .unwrap();
+        let apath = Path::new("test/anchors_unpaired.tsv");
+        unpaired_links
+            .read_anchors(apath.to_path_buf(), &mut report)
+            .unwrap();
+
+        let cells = unpaired_links.anchored_cells();
+        assert_eq!(cells, vec![0, 2, 4]);
+        assert_eq!(unpaired_links.jump_cell_id(2, 0.5), 0);
+
+        for pivot_feats in [vec![2], vec![0, 1, 3]] {
+            let sec_feats = paired_links.get_from_pivot_hits(&pivot_feats);
+            let paired = exact::process_region(
+                &sec_feats,
+                &pivot_feats,
+                &paired_links,
+                &paired_obj,
+                Some(&cells),
+                None,
+            )
+            .unwrap();
+            let unpaired = exact::process_region(
+                &sec_feats,
+                &pivot_feats,
+                &unpaired_links,
+                &unpaired_obj,
+                Some(&cells),
+                None,
+            )
+            .unwrap();
+
+            let is_close = paired
+                .values()
+                .iter()
+                .zip(unpaired.values().iter())
+                .all(|(x, y)| (x - y).abs() < 1e-5);
+            assert!(is_close);
+        }
+    }
+}
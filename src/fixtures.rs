@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use crate::links::{Links, Orientation, SkipReport};
+use crate::multimodal::MultiModalExperiment;
+
+// the paired sec and pivot test matrices
+pub fn experiment() -> MultiModalExperiment<f32> {
+    paired("test/sec", "test/pivot")
+}
+
+pub fn paired(sec: &str, pivot: &str) -> MultiModalExperiment<f32> {
+    MultiModalExperiment::from_paths(vec![
+        Path::new(sec).to_path_buf(),
+        Path::new(pivot).to_path_buf(),
+    ])
+}
+
+// the sec and pivot test matrices followed by the proteins of the same cells
+pub fn chain() -> MultiModalExperiment<f32> {
+    MultiModalExperiment::from_named_paths(
+        vec![
+            Path::new("test/sec").to_path_buf(),
+            Path::new("test/pivot").to_path_buf(),
+            Path::new("test/adt").to_path_buf(),
+        ],
+        vec!["atac".to_string(), "rna".to_string(), "adt".to_string()],
+    )
+}
+
+// links of the sec to the pivot test matrix, failing on any unknown feature
+pub fn links<'a>(mm_obj: &'a MultiModalExperiment<f32>, path: &str) -> Links<'a, f32> {
+    hop(mm_obj, path, 0, 1)
+}
+
+pub fn hop<'a>(
+    mm_obj: &'a MultiModalExperiment<f32>,
+    path: &str,
+    sec: usize,
+    pivot: usize,
+) -> Links<'a, f32> {
+    Links::new(
+        mm_obj,
+        Path::new(path).to_path_buf(),
+        sec,
+        pivot,
+        Orientation::Auto,
+        &mut SkipReport::default(),
+    )
+    .unwrap()
+}
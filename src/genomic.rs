@@ -0,0 +1,526 @@
+use clap::ArgMatches;
+use flate2::read::MultiGzDecoder;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::carina;
+
+// Half open genomic interval, 0-based as in a BED or the 10x peak names. GTF
+// records are 1-based and closed, they are converted when parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    chrom: String,
+    start: u64,
+    end: u64,
+}
+
+impl Interval {
+    // number of bases between the two intervals, zero if they overlap
+    fn distance(&self, start: u64, end: u64) -> u64 {
+        if self.end <= start {
+            start - self.end
+        } else {
+            self.start.saturating_sub(end)
+        }
+    }
+}
+
+// plain or gzipped text file
+fn open(path: &Path) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+    match path.extension() == Some(OsStr::new("gz")) {
+        true => Ok(Box::new(BufReader::new(MultiGzDecoder::new(file)))),
+        false => Ok(Box::new(BufReader::new(file))),
+    }
+}
+
+fn parse_coordinate(tok: &str, line_num: usize) -> Result<u64, Box<dyn Error>> {
+    tok.parse::<u64>()
+        .map_err(|_| format!("line {}: bad coordinate {}", line_num, tok).into())
+}
+
+// peak names look like chr1-10126-10439, chr1:10126-10439 or chr1_10126_10439,
+// the chromosome itself can have any of the separators
+pub fn parse_peak(name: &str) -> Option<Interval> {
+    let mut toks = name.rsplitn(3, ['-', ':', '_']);
+    let end = toks.next()?.parse::<u64>().ok()?;
+    let start = toks.next()?.parse::<u64>().ok()?;
+    let chrom = toks.next()?;
+    if chrom.is_empty() || start >= end {
+        return None;
+    }
+
+    Some(Interval {
+        chrom: chrom.to_string(),
+        start,
+        end,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gene {
+    name: String,
+    id: String,
+    body: Interval,
+    is_forward: bool,
+}
+
+impl Gene {
+    // first base of the gene on its strand
+    pub fn tss(&self) -> u64 {
+        match self.is_forward {
+            true => self.body.start,
+            false => self.body.end - 1,
+        }
+    }
+}
+
+// the records of a gene are merged into a single body spanning all of them
+fn merge_records(records: Vec<Gene>) -> Vec<Gene> {
+    let mut genes: Vec<Gene> = Vec::new();
+    let mut indices = HashMap::<(String, String), usize>::new();
+    for record in records {
+        let key = (record.body.chrom.clone(), record.name.clone());
+        match indices.get(&key) {
+            Some(&index) => {
+                let gene = &mut genes[index];
+                gene.body.start = gene.body.start.min(record.body.start);
+                gene.body.end = gene.body.end.max(record.body.end);
+            }
+            None => {
+                indices.insert(key, genes.len());
+                genes.push(record);
+            }
+        }
+    }
+
+    genes
+}
+
+// Genes of a GTF or GFF, named by gene_name, or else gene_id. GFF3 files of
+// Ensembl only name the gene records, i.e. the ones having a gene_id, by Name.
+// The gene_id is kept along, to match pivot features listed by their ids.
+pub fn read_gtf(path: PathBuf) -> Result<Vec<Gene>, Box<dyn Error>> {
+    let file = open(&path)?;
+
+    let mut records = Vec::new();
+    for (index, line) in file.lines().enumerate() {
+        let (line, line_num) = (line?, index + 1);
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+
+        let toks: Vec<&str> = line.split('\t').collect();
+        if toks.len() < 9 {
+            return Err(format!("line {}: GTF needs nine columns", line_num).into());
+        }
+
+        // GTF separates the keys from the values by a space, GFF3 by =
+        let mut attributes = HashMap::new();
+        for attribute in toks[8].split(';') {
+            let mut kv = attribute.trim().splitn(2, [' ', '=']);
+            if let (Some(key), Some(value)) = (kv.next(), kv.next()) {
+                attributes.insert(key, value.trim_matches('"'));
+            }
+        }
+        let name = match (attributes.get("gene_name"), attributes.get("gene_id")) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(id)) => attributes.get("Name").unwrap_or(id).to_string(),
+            (None, None) => continue,
+        };
+        let id = attributes
+            .get("gene_id")
+            .map_or(name.clone(), |x| x.to_string());
+
+        let start = parse_coordinate(toks[3], line_num)?;
+        if start == 0 {
+            return Err(format!("line {}: GTF coordinates start at 1", line_num).into());
+        }
+        let body = Interval {
+            chrom: toks[0].to_string(),
+            start: start - 1,
+            end: parse_coordinate(toks[4], line_num)?,
+        };
+        records.push(Gene {
+            name,
+            id,
+            body,
+            is_forward: toks[6] != "-",
+        });
+    }
+
+    Ok(merge_records(records))
+}
+
+// genes of a BED with the name in the fourth and the strand in the sixth
+// column, TSS files are just BEDs with single base intervals
+pub fn read_bed(path: PathBuf) -> Result<Vec<Gene>, Box<dyn Error>> {
+    let file = open(&path)?;
+
+    let mut records = Vec::new();
+    for (index, line) in file.lines().enumerate() {
+        let (line, line_num) = (line?, index + 1);
+        if line.starts_with('#') || line.starts_with("track") || line.is_empty() {
+            continue;
+        }
+
+        let toks: Vec<&str> = line.split('\t').collect();
+        if toks.len() < 4 {
+            return Err(format!("line {}: BED needs at least four columns", line_num).into());
+        }
+
+        let body = Interval {
+            chrom: toks[0].to_string(),
+            start: parse_coordinate(toks[1], line_num)?,
+            end: parse_coordinate(toks[2], line_num)?,
+        };
+        if body.start >= body.end {
+            return Err(format!("line {}: empty BED interval", line_num).into());
+        }
+        records.push(Gene {
+            name: toks[3].to_string(),
+            id: toks[3].to_string(),
+            body,
+            is_forward: toks.get(5) != Some(&"-"),
+        });
+    }
+
+    Ok(merge_records(records))
+}
+
+// Per chromosome intervals sorted by the start, along with the longest one.
+// An interval overlapping a query can't start more than the longest interval
+// before the query, which bounds the scan.
+pub struct IntervalIndex {
+    chroms: HashMap<String, ChromIntervals>,
+}
+
+// (start, end, index) of the intervals of a chromosome and the longest length
+type ChromIntervals = (Vec<(u64, u64, usize)>, u64);
+
+impl IntervalIndex {
+    pub fn new(intervals: Vec<(&Interval, usize)>) -> IntervalIndex {
+        let mut chroms = HashMap::<String, ChromIntervals>::new();
+        for (interval, index) in intervals {
+            let entry = chroms
+                .entry(interval.chrom.clone())
+                .or_insert((Vec::new(), 0));
+            entry.0.push((interval.start, interval.end, index));
+            entry.1 = entry.1.max(interval.end - interval.start);
+        }
+
+        for (entries, _) in chroms.values_mut() {
+            entries.sort();
+        }
+
+        IntervalIndex { chroms }
+    }
+
+    // indices of the intervals overlapping the half open query interval
+    pub fn query(&self, chrom: &str, start: u64, end: u64) -> Vec<usize> {
+        let (entries, max_len) = match self.chroms.get(chrom) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+
+        let lower = start.saturating_sub(*max_len);
+        let first = entries.partition_point(|x| x.0 < lower);
+        entries[first..]
+            .iter()
+            .take_while(|x| x.0 < end)
+            .filter(|x| x.1 > start)
+            .map(|x| x.2)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkMode {
+    Tss,
+    Body,
+}
+
+// (peak, gene, distance) for all the peaks within the window of the TSS, or
+// of the gene body, sorted by the peak
+pub fn find_links(
+    peaks: &[Option<Interval>],
+    genes: &[Gene],
+    mode: LinkMode,
+    window: u64,
+) -> Vec<(usize, usize, u64)> {
+    let index = IntervalIndex::new(
+        peaks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, peak)| peak.as_ref().map(|x| (x, index)))
+            .collect(),
+    );
+
+    let mut links = Vec::new();
+    for (gene_index, gene) in genes.iter().enumerate() {
+        let (start, end) = match mode {
+            LinkMode::Tss => (gene.tss(), gene.tss() + 1),
+            LinkMode::Body => (gene.body.start, gene.body.end),
+        };
+
+        let hits = index.query(&gene.body.chrom, start.saturating_sub(window), end + window);
+        for peak_index in hits {
+            let peak = peaks[peak_index].as_ref().unwrap();
+            links.push((peak_index, gene_index, peak.distance(start, end)));
+        }
+    }
+
+    links.sort();
+    links
+}
+
+// (id, name) of every feature, 10x features files list the id, the name and
+// the type, older genes files the id and the name, while plain ones only have
+// the name, which is then also the id
+fn read_features(path: PathBuf) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let file = open(&path)?;
+
+    let mut features = Vec::new();
+    for (index, line) in file.lines().enumerate() {
+        let (line, line_num) = (line?, index + 1);
+        let mut toks = line.split('\t');
+        let id = toks.next().unwrap_or_default().to_string();
+        if id.is_empty() {
+            return Err(format!("line {}: feature without an id", line_num).into());
+        }
+        let name = toks.next().map_or(id.clone(), |x| x.to_string());
+        features.push((id, name));
+    }
+
+    Ok(features)
+}
+
+// Keeps the genes whose name or id is either the id or the name of a pivot
+// feature, renamed to the identifier of the feature they matched, by name
+// first, for the links to name them as the pivot does. Returns the number of
+// pivot features missing from the annotation.
+fn retain_genes(genes: &mut Vec<Gene>, features: &[(String, String)]) -> usize {
+    let num_missing = {
+        let annotated: HashSet<&String> = genes.iter().flat_map(|x| [&x.name, &x.id]).collect();
+        features
+            .iter()
+            .filter(|(id, name)| !annotated.contains(id) && !annotated.contains(name))
+            .count()
+    };
+
+    let known: HashSet<&String> = features.iter().flat_map(|(id, name)| [id, name]).collect();
+    for gene in genes.iter_mut() {
+        if !known.contains(&gene.name) && known.contains(&gene.id) {
+            gene.name = gene.id.clone();
+        }
+    }
+    genes.retain(|x| known.contains(&x.name));
+    num_missing
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let features_path = carina::file::file_path_from_clap(sub_m, "features")?;
+    let annotation_path = carina::file::file_path_from_clap(sub_m, "annotation")?;
+    let mut ofile = carina::file::bufwriter_from_clap(sub_m, "output")?;
+
+    let mode = match sub_m.value_of("mode") {
+        Some("body") => LinkMode::Body,
+        _ => LinkMode::Tss,
+    };
+    let window = sub_m.value_of("window").unwrap().parse::<u64>()?;
+    let decay = match sub_m.value_of("decay") {
+        Some(decay) => Some(decay.parse::<f32>()?),
+        None => None,
+    };
+    if matches!(decay, Some(x) if x <= 0.0) {
+        return Err("distance decay has to be positive".into());
+    }
+
+    let names: Vec<String> = read_features(features_path)?
+        .into_iter()
+        .map(|x| x.1)
+        .collect();
+    let peaks: Vec<Option<Interval>> = names.iter().map(|x| parse_peak(x)).collect();
+    let num_skipped = peaks.iter().filter(|x| x.is_none()).count();
+    if num_skipped > 0 {
+        warn!("Skipping {} features not named as peaks", num_skipped);
+    }
+    info!("Found {} peaks", peaks.len() - num_skipped);
+
+    // GTF and GFF files, possibly gzipped, anything else is read as a BED
+    let name = annotation_path
+        .to_str()
+        .unwrap_or("")
+        .trim_end_matches(".gz");
+    let is_gtf = [".gtf", ".gff", ".gff3"].iter().any(|x| name.ends_with(x));
+    let mut genes = match is_gtf {
+        true => read_gtf(annotation_path)?,
+        false => read_bed(annotation_path)?,
+    };
+
+    // links to genes absent from the pivot matrix can't be used
+    if let Some(pivot_path) = sub_m.value_of("genes") {
+        let pivot_genes = read_features(PathBuf::from(pivot_path))?;
+        let num_missing = retain_genes(&mut genes, &pivot_genes);
+        if num_missing > 0 {
+            warn!("{} pivot genes are not in the annotation", num_missing);
+        }
+    }
+    info!("Found {} genes", genes.len());
+
+    let links = find_links(&peaks, &genes, mode, window);
+    info!("Writing {} links", links.len());
+    for (peak_index, gene_index, distance) in links {
+        write!(ofile, "{}\t{}", names[peak_index], genes[gene_index].name)?;
+        match decay {
+            Some(decay) => writeln!(ofile, "\t{}", (-(distance as f32) / decay).exp())?,
+            None => writeln!(ofile)?,
+        };
+    }
+
+    info!("All done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::genomic;
+    use crate::genomic::{Interval, IntervalIndex, LinkMode};
+
+    #[test]
+    fn test_parse_peak() {
+        let expected = Some(Interval {
+            chrom: "chr1".to_string(),
+            start: 10126,
+            end: 10439,
+        });
+        assert_eq!(genomic::parse_peak("chr1-10126-10439"), expected);
+        assert_eq!(genomic::parse_peak("chr1:10126-10439"), expected);
+        assert_eq!(genomic::parse_peak("chr1_10126_10439"), expected);
+
+        let peak = genomic::parse_peak("chrUn_KI270742v1-10-20").unwrap();
+        assert_eq!(peak.chrom, "chrUn_KI270742v1");
+        assert_eq!(genomic::parse_peak("FAM138A"), None);
+        assert_eq!(genomic::parse_peak("chr1-20-10"), None);
+    }
+
+    #[test]
+    fn test_interval_index() {
+        let intervals = [
+            genomic::parse_peak("chr1-100-200").unwrap(),
+            genomic::parse_peak("chr1-150-1000").unwrap(),
+            genomic::parse_peak("chr1-300-400").unwrap(),
+            genomic::parse_peak("chr2-100-200").unwrap(),
+        ];
+        let index = IntervalIndex::new(intervals.iter().zip(0..).collect());
+
+        let mut hits = index.query("chr1", 250, 320);
+        hits.sort();
+        assert_eq!(hits, vec![1, 2]);
+        assert_eq!(index.query("chr1", 1001, 2000), Vec::<usize>::new());
+        assert_eq!(index.query("chr2", 199, 200), vec![3]);
+        assert_eq!(index.query("chr2", 200, 201), Vec::<usize>::new());
+        assert_eq!(index.query("chrX", 0, 1000), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_read_annotation() {
+        // GFF3 of Ensembl names only the gene records, by Name or else gene_id
+        let genes = genomic::read_gtf(Path::new("test/genes.gff3.gz").to_path_buf()).unwrap();
+        let names: Vec<&str> = genes.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["OR4F5", "ENSG01"]);
+        assert_eq!((genes[0].tss(), genes[1].tss()), (8999, 12999));
+
+        let err = genomic::read_gtf(Path::new("test/genes_malformed.gtf").to_path_buf())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "line 2: GTF needs nine columns");
+        let err = genomic::read_bed(Path::new("test/genes_malformed.gtf").to_path_buf())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "line 1: bad coordinate test");
+    }
+
+    #[test]
+    fn test_find_links() {
+        let features =
+            genomic::read_features(Path::new("test/sec/genes.tsv").to_path_buf()).unwrap();
+        assert_eq!(features[0].0, features[0].1);
+        let peaks: Vec<Option<genomic::Interval>> =
+            features.iter().map(|x| genomic::parse_peak(&x.1)).collect();
+
+        let genes = genomic::read_gtf(Path::new("test/genes.gtf").to_path_buf()).unwrap();
+        let names: Vec<&str> = genes.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "FAM138A",
+                "OR4F5",
+                "WASH7P",
+                "AP006222.2",
+                "OR4F16",
+                "SH3YL1"
+            ]
+        );
+        assert_eq!((genes[0].tss(), genes[1].tss()), (12999, 8999));
+
+        let links = genomic::find_links(&peaks, &genes, LinkMode::Tss, 10_000);
+        assert_eq!(
+            links,
+            vec![
+                (0, 0, 2560),
+                (0, 1, 1126),
+                (0, 2, 8560),
+                (1, 3, 8851),
+                (2, 3, 8294),
+                (3, 3, 1396),
+                (6, 4, 873),
+                (7, 4, 2995),
+            ]
+        );
+
+        // the TSS BED gives the same links around the TSS
+        let tss = genomic::read_bed(Path::new("test/tss.bed").to_path_buf()).unwrap();
+        assert_eq!(
+            genomic::find_links(&peaks, &tss, LinkMode::Tss, 10_000),
+            links
+        );
+
+        // the 10x features list the ids first, the last gene only by its id,
+        // which the links then name it by
+        let features =
+            genomic::read_features(Path::new("test/features.tsv").to_path_buf()).unwrap();
+        assert_eq!(features[0], ("ENSG02".to_string(), "OR4F5".to_string()));
+        assert_eq!(
+            genomic::read_features(Path::new("test/features.tsv.gz").to_path_buf()).unwrap(),
+            features
+        );
+        let mut kept = genes.clone();
+        assert_eq!(genomic::retain_genes(&mut kept, &features), 0);
+        let names: Vec<&str> = kept.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["OR4F5", "AP006222.2", "OR4F16", "ENSG06"]);
+
+        let err = genomic::read_features(Path::new("test/features_blank.tsv").to_path_buf())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "line 2: feature without an id");
+
+        let links = genomic::find_links(&peaks, &genes, LinkMode::Body, 2_000);
+        assert_eq!(
+            links,
+            vec![
+                (0, 0, 1560),
+                (0, 1, 326),
+                (3, 3, 1396),
+                (6, 4, 873),
+                (7, 4, 1995)
+            ]
+        );
+    }
+}
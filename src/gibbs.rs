@@ -5,53 +5,312 @@ use std::io::Write;
 
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::distributions::{Distribution, Uniform};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use crossbeam::queue::ArrayQueue;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
+use crate::alias;
+use crate::bootstrap;
+use crate::configs;
+use crate::exact;
 use crate::links;
+use crate::mtx;
 use crate::multimodal;
+use crate::permutation;
+use crate::summary;
 
 #[derive(Debug)]
 pub struct Gamma {
     num_rows: usize,
     num_cols: usize,
     stats: Vec<u32>,
+    values: Vec<f32>,
+    rhat: Vec<f32>,
+    ess: Vec<f32>,
+    converged: bool,
+    intervals: Option<Vec<bootstrap::Interval>>,
+    pvalues: Option<Vec<f32>>,
+    qvalues: Option<Vec<f32>>,
+    cell_stats: Option<HashMap<(usize, usize), u32>>,
 }
 
 impl Gamma {
+    // noise-free gamma from an exactly solved stationary distribution, there
+    // are no raw counts backing it
+    pub fn from_probabilities(num_rows: usize, num_cols: usize, values: Vec<f32>) -> Gamma {
+        let num_states = values.len();
+        Gamma {
+            num_rows,
+            num_cols,
+            stats: Vec::new(),
+            values,
+            rhat: vec![f32::NAN; num_states],
+            ess: vec![f32::NAN; num_states],
+            converged: true,
+            intervals: None,
+            pvalues: None,
+            qvalues: None,
+            cell_stats: None,
+        }
+    }
+
     pub fn write(
         &self,
         ofile: &mut BufWriter<File>,
-        mm_obj: &multimodal::MultiModalExperiment<f32>,
-        sec_feats: &Vec<usize>,
-        pivot_feats: &Vec<usize>,
-        region_id: usize,
+        links_obj: &links::Links<f32>,
+        sec_feats: &[usize],
+        pivot_feats: &[usize],
+        region_id: &str,
     ) -> Result<(), Box<dyn Error>> {
-        let norm: u32 = self.stats.iter().sum();
-        for (mat_index, val) in self.stats.iter().enumerate() {
-            if *val == 0 {
+        let (sec_marginals, pivot_marginals) = self.marginals();
+        let total = match self.stats.is_empty() {
+            true => "NA".to_string(),
+            false => self.stats.iter().sum::<u32>().to_string(),
+        };
+
+        for (mat_index, val) in self.values.iter().enumerate() {
+            if *val == 0.0 {
                 continue;
             }
             let state = State::new(mat_index, self.num_rows, self.num_cols);
+            let count = self
+                .stats
+                .get(mat_index)
+                .map_or("NA".to_string(), |x| x.to_string());
 
             write!(
                 ofile,
-                "{}\t{}\t{}\t{}\n",
-                mm_obj.get_feature_string(false, sec_feats[state.sec]),
-                mm_obj.get_feature_string(true, pivot_feats[state.pivot]),
-                //val,
-                *val as f32 / norm as f32,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                links_obj.feature_string(false, sec_feats[state.sec]),
+                links_obj.feature_string(true, pivot_feats[state.pivot]),
+                val,
                 region_id,
+                self.rhat[mat_index],
+                self.ess[mat_index],
+                self.converged,
+                count,
+                total,
+                self.num_rows,
+                self.num_cols,
+                sec_marginals[state.sec],
+                pivot_marginals[state.pivot],
             )?;
+
+            if let Some(intervals) = &self.intervals {
+                let interval = &intervals[mat_index];
+                write!(
+                    ofile,
+                    "\t{}\t{}\t{}\t{}",
+                    interval.mean, interval.sd, interval.lo, interval.hi
+                )?;
+            }
+            if let (Some(pvalues), Some(qvalues)) = (&self.pvalues, &self.qvalues) {
+                write!(ofile, "\t{}\t{}", pvalues[mat_index], qvalues[mat_index])?;
+            }
+            writeln!(ofile)?;
         }
         Ok(())
     }
 
-    pub fn _stats(&self) -> &Vec<u32> {
+    // frequency of every sec and every pivot feature of the region, summed
+    // over the links it takes part in
+    pub fn marginals(&self) -> (Vec<f32>, Vec<f32>) {
+        let mut sec_marginals = vec![0.0_f32; self.num_rows];
+        let mut pivot_marginals = vec![0.0_f32; self.num_cols];
+        for (mat_index, val) in self.values.iter().enumerate() {
+            let state = State::new(mat_index, self.num_rows, self.num_cols);
+            sec_marginals[state.sec] += val;
+            pivot_marginals[state.pivot] += val;
+        }
+
+        (sec_marginals, pivot_marginals)
+    }
+
+    pub fn set_intervals(&mut self, intervals: Vec<bootstrap::Interval>) {
+        self.intervals = Some(intervals);
+    }
+
+    pub fn pvalues(&self) -> Option<&[f32]> {
+        self.pvalues.as_deref()
+    }
+
+    pub fn set_pvalues(&mut self, pvalues: Vec<f32>) {
+        self.pvalues = Some(pvalues);
+    }
+
+    pub fn set_qvalues(&mut self, qvalues: Vec<f32>) {
+        self.qvalues = Some(qvalues);
+    }
+
+    // (global cell id, link index) -> number of recorded samples the cell drove
+    pub fn cell_stats(&self) -> Option<&HashMap<(usize, usize), u32>> {
+        self.cell_stats.as_ref()
+    }
+
+    pub fn stats(&self) -> &Vec<u32> {
         &self.stats
     }
+
+    pub fn values(&self) -> &Vec<f32> {
+        &self.values
+    }
+
+    pub fn rhat(&self) -> &Vec<f32> {
+        &self.rhat
+    }
+
+    pub fn ess(&self) -> &Vec<f32> {
+        &self.ess
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.converged
+    }
+}
+
+// a single markov chain with its link counts, overall and per batch of
+// consecutive samples, and optionally per sampled cell
+#[derive(Debug)]
+struct Chain<S> {
+    state: S,
+    rng: StdRng,
+    num_samples: usize,
+    batch_size: usize,
+    stats: Vec<u32>,
+    batches: Vec<Vec<u32>>,
+    cell_stats: Option<HashMap<(usize, usize), u32>>,
+}
+
+impl<S> Chain<S> {
+    fn new(state: S, rng: StdRng, num_states: usize, batch_size: usize) -> Chain<S> {
+        Chain {
+            state,
+            rng,
+            num_samples: 0,
+            batch_size: batch_size.max(1),
+            stats: vec![0_u32; num_states],
+            batches: Vec::new(),
+            cell_stats: None,
+        }
+    }
+
+    fn record(&mut self, index: usize) {
+        let batch = self.num_samples / self.batch_size;
+        if batch == self.batches.len() {
+            self.batches.push(vec![0_u32; self.stats.len()]);
+        }
+
+        self.batches[batch][index] += 1;
+        self.stats[index] += 1;
+        self.num_samples += 1;
+    }
+
+    fn record_cell(&mut self, cell_id: usize, index: usize) {
+        if let Some(cell_stats) = self.cell_stats.as_mut() {
+            *cell_stats.entry((cell_id, index)).or_insert(0) += 1;
+        }
+    }
+}
+
+// Gelman-Rubin potential scale reduction factor and batch means effective
+// sample size for every link, treating each link's visit indicator as the
+// scalar being tracked by the chains.
+fn diagnostics<S>(chains: &[Chain<S>]) -> (Vec<f32>, Vec<f32>) {
+    let num_chains = chains.len();
+    let num_states = chains[0].stats.len();
+    let num_samples = chains[0].num_samples;
+    let n = num_samples as f64;
+    let m = num_chains as f64;
+
+    // only completely filled batches are used for the batch means
+    let batch_size = chains[0].batch_size;
+    let num_full_batches = num_samples / batch_size;
+
+    let mut rhat = vec![f32::NAN; num_states];
+    let mut ess = vec![(m * n) as f32; num_states];
+    for index in 0..num_states {
+        let means: Vec<f64> = chains
+            .iter()
+            .map(|chain| chain.stats[index] as f64 / n)
+            .collect();
+        let mean = means.iter().sum::<f64>() / m;
+
+        if num_chains > 1 && num_samples > 1 {
+            let w = means
+                .iter()
+                .map(|p| p * (1.0 - p) * n / (n - 1.0))
+                .sum::<f64>()
+                / m;
+            let b = n * means.iter().map(|p| (p - mean).powi(2)).sum::<f64>() / (m - 1.0);
+            let var_plus = ((n - 1.0) / n) * w + b / n;
+
+            rhat[index] = match w > 0.0 {
+                true => (var_plus / w).sqrt() as f32,
+                false if b > 0.0 => f32::INFINITY,
+                false => 1.0,
+            };
+        }
+
+        let num_batches = (num_chains * num_full_batches) as f64;
+        if num_batches < 2.0 {
+            continue;
+        }
+
+        let batch_var = chains
+            .iter()
+            .flat_map(|chain| chain.batches.iter().take(num_full_batches))
+            .map(|batch| (batch[index] as f64 / batch_size as f64 - mean).powi(2))
+            .sum::<f64>()
+            / (num_batches - 1.0);
+
+        let sample_var = mean * (1.0 - mean);
+        if batch_var > 0.0 {
+            ess[index] = (m * n * sample_var / (batch_size as f64 * batch_var)).min(m * n) as f32;
+        }
+    }
+
+    (rhat, ess)
+}
+
+fn pooled_stats<S>(chains: &[Chain<S>]) -> Vec<u32> {
+    let mut stats = vec![0_u32; chains[0].stats.len()];
+    for chain in chains {
+        for (index, val) in chain.stats.iter().enumerate() {
+            stats[index] += val;
+        }
+    }
+
+    stats
+}
+
+fn pooled_cell_stats<S>(chains: &[Chain<S>]) -> Option<HashMap<(usize, usize), u32>> {
+    let mut pooled: Option<HashMap<(usize, usize), u32>> = None;
+    for cell_stats in chains.iter().filter_map(|chain| chain.cell_stats.as_ref()) {
+        let pooled = pooled.get_or_insert_with(HashMap::new);
+        for (key, val) in cell_stats {
+            *pooled.entry(*key).or_insert(0) += val;
+        }
+    }
+
+    pooled
+}
+
+// largest Monte-Carlo standard error among the visited links
+fn max_mcse(stats: &[u32], ess: &[f32]) -> f32 {
+    let norm: u32 = stats.iter().sum();
+    stats
+        .iter()
+        .zip(ess.iter())
+        .filter(|(&val, _)| val > 0)
+        .map(|(&val, &ess)| {
+            let p = val as f32 / norm as f32;
+            (p * (1.0 - p) / ess).sqrt()
+        })
+        .fold(0.0_f32, f32::max)
 }
 
 #[derive(PartialEq, Debug)]
@@ -89,104 +348,490 @@ impl State {
     }
 }
 
-pub fn process_region(
-    sec_feats: &Vec<usize>,
-    pivot_feats: &Vec<usize>,
-    num_samples: usize,
-    links_obj: &links::Links<f32>,
-    mm_obj: &multimodal::MultiModalExperiment<f32>,
-    cells: Option<&Vec<usize>>,
-) -> Result<Gamma, Box<dyn Error>> {
-    let num_cells = match cells {
-        Some(cells) => cells.len(),
-        None => mm_obj.num_cells(),
+// FNV-1a hash of some leading bytes and of groups of feature names, e.g. the
+// pivot then the sec features of a region.
+pub fn feature_hash(prefix: &[u8], groups: &[Vec<&str>]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut update = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
     };
 
-    let num_sec_feats = sec_feats.len();
-    let num_pivot_feats = pivot_feats.len();
+    update(prefix);
+    for (index, names) in groups.iter().enumerate() {
+        if index > 0 {
+            update(b"\n");
+        }
+        for name in names {
+            update(name.as_bytes());
+            update(b"\t");
+        }
+    }
 
-    let pivot_dist = Uniform::from(0..num_pivot_feats);
-    let sec_dist = Uniform::from(0..num_sec_feats);
-    let cells_dist = Uniform::from(0..num_cells);
+    hash
+}
 
-    let mut rng = rand::thread_rng();
-    let mut state = State {
-        sec: sec_dist.sample(&mut rng),
-        pivot: pivot_dist.sample(&mut rng),
+fn region_hash(
+    prefix: &[u8],
+    links_obj: &links::Links<f32>,
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+) -> u64 {
+    let names = |feats: &[usize], is_pivot: bool| {
+        feats
+            .iter()
+            .map(|&x| links_obj.feature_string(is_pivot, x))
+            .collect()
     };
+    feature_hash(prefix, &[names(pivot_feats, true), names(sec_feats, false)])
+}
 
-    // keeping the full pivot matrix while smaller sec matrix
-    let pivot_mat = mm_obj.get_dense_submatrix(None, pivot_feats, true);
-    let sec_mat = mm_obj.get_dense_submatrix(cells, sec_feats, false);
+// Hashing in the user seed makes the random stream of a region independent of
+// the thread processing it.
+pub fn region_seed(
+    seed: u64,
+    links_obj: &links::Links<f32>,
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+) -> u64 {
+    region_hash(&seed.to_le_bytes(), links_obj, sec_feats, pivot_feats)
+}
 
-    let mut stats = vec![0_u32; num_sec_feats * num_pivot_feats];
-    for _ in 0..num_samples {
-        // sample a cell
-        // looking for cell id in the submatrix
-        let cell_id_sec = cells_dist.sample(&mut rng);
-        // match cells.is_some() {
-        //     true => cells.unwrap()[cells_dist.sample(&mut rng)],
-        //     false => cells_dist.sample(&mut rng),
-        // };
+// Seeds of a procedure resampling the region, e.g. the bootstrap or the
+// permutations. Tagging the hash with the procedure keeps its random streams
+// apart from the chains of the region and from the other procedures, with
+// replicate 0 driving the resampling itself and every other replicate the
+// chains of one resampled region.
+pub fn stream_seed(
+    seed: u64,
+    stream: &str,
+    replicate: usize,
+    links_obj: &links::Links<f32>,
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+) -> u64 {
+    let mut prefix = seed.to_le_bytes().to_vec();
+    prefix.extend_from_slice(stream.as_bytes());
+    prefix.extend_from_slice(&(replicate as u64).to_le_bytes());
+    region_hash(&prefix, links_obj, sec_feats, pivot_feats)
+}
+
+// Identifies a region by its member features, stable across runs and seeds.
+pub fn region_id(
+    links_obj: &links::Links<f32>,
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+) -> String {
+    format!(
+        "{:016x}",
+        region_hash(&[], links_obj, sec_feats, pivot_feats)
+    )
+}
+
+// Markov chain over the states of a region, every step being driven by one
+// sampled cell.
+pub trait Walk {
+    type State;
+
+    fn num_states(&self) -> usize;
+
+    // every chain starts from its own random state
+    fn init_state(&self, rng: &mut StdRng) -> Self::State;
+
+    // returns the global id of the cell driving the step
+    fn step(&self, state: &mut Self::State, rng: &mut StdRng) -> Result<usize, Box<dyn Error>>;
+
+    // position of the state in the gamma of the region
+    fn state_index(&self, state: &Self::State) -> usize;
+}
+
+// region specific view of the data shared by all the chains of a region
+struct RegionSampler<'a> {
+    sec_feats: &'a [usize],
+    pivot_feats: &'a [usize],
+    links_obj: &'a links::Links<'a, f32>,
+    cell_index: multimodal::CellIndex<'a>,
+    permutation: Option<&'a [usize]>,
+    sec_mat: multimodal::SubMatrix,
+    pivot_mat: multimodal::SubMatrix,
+    sec_hoods: Vec<alias::Neighbourhood>,
+    pivot_hoods: Vec<alias::Neighbourhood>,
+    cells_dist: Uniform<usize>,
+}
+
+impl<'a> RegionSampler<'a> {
+    fn new(
+        sec_feats: &'a [usize],
+        pivot_feats: &'a [usize],
+        links_obj: &'a links::Links<'a, f32>,
+        mm_obj: &'a multimodal::MultiModalExperiment<f32>,
+        cells: Option<&'a [usize]>,
+        permutation: Option<&'a [usize]>,
+    ) -> RegionSampler<'a> {
+        // the sec matrix only holds the cells driving the chain, while the pivot
+        // matrix keeps all the cells the anchors or a permutation can jump to
+        let cell_index = mm_obj.cell_index(links_obj.sec_assay(), cells);
+        let pivot_mat = mm_obj.get_submatrix(
+            &mm_obj.cell_index(links_obj.pivot_assay(), None),
+            pivot_feats,
+            links_obj.pivot_assay(),
+        );
+        let sec_mat = mm_obj.get_submatrix(&cell_index, sec_feats, links_obj.sec_assay());
+        let cells_dist = Uniform::from(0..sec_mat.num_rows());
+        debug!(
+            "Region submatrices hold {} sec & {} pivot nonzeros",
+            sec_mat.nnz(),
+            pivot_mat.nnz()
+        );
+
+        // sec_hoods[p] draws a sec position given the pivot position p and
+        // pivot_hoods[s] a pivot position given the sec position s
+        let (sec_indices, pivot_indices) = links_obj.local_links(sec_feats, pivot_feats);
+        let (sec_weights, pivot_weights) = links_obj.local_weights(sec_feats, pivot_feats);
+        let sec_hoods = alias::build_neighbourhoods(&sec_indices, &sec_weights);
+        let pivot_hoods = alias::build_neighbourhoods(&pivot_indices, &pivot_weights);
+
+        RegionSampler {
+            sec_feats,
+            pivot_feats,
+            links_obj,
+            cell_index,
+            permutation,
+            sec_mat,
+            pivot_mat,
+            sec_hoods,
+            pivot_hoods,
+            cells_dist,
+        }
+    }
+}
+
+impl<'a> Walk for RegionSampler<'a> {
+    type State = State;
+
+    fn num_states(&self) -> usize {
+        self.sec_feats.len() * self.pivot_feats.len()
+    }
+
+    fn init_state(&self, rng: &mut StdRng) -> State {
+        State {
+            sec: Uniform::from(0..self.sec_feats.len()).sample(rng),
+            pivot: Uniform::from(0..self.pivot_feats.len()).sample(rng),
+        }
+    }
+
+    fn step(&self, state: &mut State, rng: &mut StdRng) -> Result<usize, Box<dyn Error>> {
+        // sample a cell, as a local row of the sec submatrix
+        let row = self.cells_dist.sample(rng);
+        let cell_id = self.cell_index.to_global(row);
 
         {
             // sample from sec
             let coin_toss_value: f32 = rng.gen_range(0.0, 1.0);
-
-            let pivot_feat = pivot_feats[state.pivot];
-            let sec_hits = links_obj.entry_from_pivot(pivot_feat);
-            let sec_indices: Vec<usize> = sec_hits
-                .into_iter()
-                .map(|x| sec_feats.iter().position(|y| y == x).unwrap())
-                .collect();
-
-            state.sec =
-                mm_obj.choose_feature(&sec_mat, &sec_indices, coin_toss_value, cell_id_sec)?;
+            state.sec = self.sec_hoods[state.pivot].sample(&self.sec_mat, row, coin_toss_value);
         }
 
         {
             // sample from the anchors
+            // anchors and the full pivot matrix are indexed by global cell ids
             let coin_toss_value: f32 = rng.gen_range(0.0, 1.0);
-            let pivot_cell = links_obj.jump_cell_id(cell_id_sec, coin_toss_value);
-            // let pivot_cell_sub_matrix = match links_obj.has_anchors() {
-            //     true => cells.unwrap().iter().position(|&x| x == pivot_cell).unwrap(),
-            //     false => pivot_cell,
-            // };
+            let pivot_cell = self.links_obj.jump_cell_id(cell_id, coin_toss_value);
+            let pivot_cell = self.permutation.map_or(pivot_cell, |perm| perm[pivot_cell]);
 
             // sample from pivot
             let coin_toss_value: f32 = rng.gen_range(0.0, 1.0);
-            let sec_feat = sec_feats[state.sec];
-            let pivot_hits = links_obj.entry_to_pivot(sec_feat);
-            let pivot_indices: Vec<usize> = pivot_hits
-                .into_iter()
-                .map(|x| pivot_feats.iter().position(|y| y == x).unwrap())
-                .collect();
-
-            state.pivot = mm_obj.choose_feature(
-                &pivot_mat,
-                &pivot_indices,
-                coin_toss_value,
-                //pivot_cell_sub_matrix,
-                pivot_cell,
-            )?;
+            state.pivot =
+                self.pivot_hoods[state.sec].sample(&self.pivot_mat, pivot_cell, coin_toss_value);
         }
 
-        stats[state.row_major_index(num_pivot_feats)] += 1;
+        Ok(cell_id)
+    }
+
+    fn state_index(&self, state: &State) -> usize {
+        state.row_major_index(self.pivot_feats.len())
+    }
+}
+
+// advances the chain without recording, used for the burn-in
+fn skip<W: Walk>(
+    walk: &W,
+    chain: &mut Chain<W::State>,
+    num_iters: usize,
+) -> Result<(), Box<dyn Error>> {
+    for _ in 0..num_iters {
+        walk.step(&mut chain.state, &mut chain.rng)?;
     }
 
+    Ok(())
+}
+
+// records num_samples states, keeping only every thin-th one
+fn run<W: Walk>(
+    walk: &W,
+    chain: &mut Chain<W::State>,
+    num_samples: usize,
+    thin: usize,
+) -> Result<(), Box<dyn Error>> {
+    for _ in 0..num_samples {
+        skip(walk, chain, thin - 1)?;
+        let cell_id = walk.step(&mut chain.state, &mut chain.rng)?;
+        let index = walk.state_index(&chain.state);
+        chain.record(index);
+        chain.record_cell(cell_id, index);
+    }
+
+    Ok(())
+}
+
+// Runs the chains of a region from the given seed and pools their counts into
+// a gamma laid out as num_rows by num_cols states.
+pub fn sample_region<W: Walk>(
+    walk: &W,
+    config: &configs::GibbsConfig,
+    seed: u64,
+    num_rows: usize,
+    num_cols: usize,
+) -> Result<Gamma, Box<dyn Error>> {
+    let num_chains = config.num_chains.max(1);
+    let thin = config.thin.max(1);
+    let budget = config.sample_budget(walk.num_states());
+    let mut num_samples = (budget / num_chains).max(1);
+
+    let mut chains = Vec::with_capacity(num_chains);
+    for chain_id in 0..num_chains {
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(chain_id as u64));
+        let state = walk.init_state(&mut rng);
+        let batch_size = num_samples / configs::NUM_BATCHES;
+        let mut chain = Chain::new(state, rng, walk.num_states(), batch_size);
+        if config.cell_attribution {
+            chain.cell_stats = Some(HashMap::new());
+        }
+
+        skip(walk, &mut chain, config.burn_in)?;
+        run(walk, &mut chain, num_samples, thin)?;
+        chains.push(chain);
+    }
+
+    let mut stats = pooled_stats(&chains);
+    let (mut rhat, mut ess) = diagnostics(&chains);
+
+    // keep doubling the samples of every chain until the target error or the
+    // cap is reached
+    if let Some(target_mcse) = config.target_mcse {
+        let max_samples = (config.max_samples / num_chains).max(1);
+        while max_mcse(&stats, &ess) > target_mcse && num_samples < max_samples {
+            let extension = num_samples.min(max_samples - num_samples);
+            for chain in chains.iter_mut() {
+                run(walk, chain, extension, thin)?;
+            }
+            num_samples += extension;
+
+            stats = pooled_stats(&chains);
+            let (new_rhat, new_ess) = diagnostics(&chains);
+            rhat = new_rhat;
+            ess = new_ess;
+        }
+    }
+    debug!(
+        "Used {} samples per chain for a region with {} states",
+        num_samples,
+        walk.num_states()
+    );
+
+    let converged = stats
+        .iter()
+        .zip(rhat.iter())
+        .all(|(&val, &rhat)| val == 0 || rhat.is_nan() || rhat <= config.rhat_threshold);
+
+    let norm: u32 = stats.iter().sum();
+    let values = stats.iter().map(|&x| x as f32 / norm as f32).collect();
+    let cell_stats = pooled_cell_stats(&chains);
+
     Ok(Gamma {
-        num_rows: num_sec_feats,
-        num_cols: num_pivot_feats,
+        num_rows,
+        num_cols,
         stats,
+        values,
+        rhat,
+        ess,
+        converged,
+        intervals: None,
+        pvalues: None,
+        qvalues: None,
+        cell_stats,
     })
 }
 
+pub fn process_region(
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+    config: &configs::GibbsConfig,
+    links_obj: &links::Links<f32>,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    cells: Option<&[usize]>,
+    permutation: Option<&[usize]>,
+) -> Result<Gamma, Box<dyn Error>> {
+    let sampler = RegionSampler::new(
+        sec_feats,
+        pivot_feats,
+        links_obj,
+        mm_obj,
+        cells,
+        permutation,
+    );
+
+    let seed = region_seed(config.seed, links_obj, sec_feats, pivot_feats);
+    sample_region(&sampler, config, seed, sec_feats.len(), pivot_feats.len())
+}
+
+// Solves small regions exactly and samples the rest, regions are always sampled
+// when the cells driving the chain are tracked.
+pub fn estimate_region(
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+    config: &configs::GibbsConfig,
+    links_obj: &links::Links<f32>,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    cells: Option<&[usize]>,
+    permutation: Option<&[usize]>,
+) -> Result<Gamma, Box<dyn Error>> {
+    match config.is_exact(sec_feats.len() * pivot_feats.len()) {
+        true => exact::process_region(
+            sec_feats,
+            pivot_feats,
+            links_obj,
+            mm_obj,
+            cells,
+            permutation,
+        ),
+        false => process_region(
+            sec_feats,
+            pivot_feats,
+            config,
+            links_obj,
+            mm_obj,
+            cells,
+            permutation,
+        ),
+    }
+}
+
+pub fn write_header(
+    ofile: &mut BufWriter<File>,
+    config: &configs::GibbsConfig,
+    links_obj: &links::Links<f32>,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(ofile, "# links={}", links_obj.name())?;
+    write_config(ofile, config)?;
+
+    write!(
+        ofile,
+        "sec\tpivot\tgamma\tregion_id\trhat\tess\tconverged\tcount\ttotal_samples\tnum_secs\tnum_pivots\tsec_freq\tpivot_freq"
+    )?;
+    if config.num_bootstraps > 0 {
+        write!(ofile, "\tboot_mean\tboot_sd\tboot_lo\tboot_hi")?;
+    }
+    if config.num_permutations > 0 {
+        write!(ofile, "\tpvalue\tqvalue")?;
+    }
+    writeln!(ofile)?;
+
+    Ok(())
+}
+
+// the settings behind a gamma table, as comment lines
+pub fn write_config(
+    ofile: &mut BufWriter<File>,
+    config: &configs::GibbsConfig,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        ofile,
+        "# min_samples={} max_samples={} samples_per_state={} target_mcse={:?} num_chains={} burn_in={} thin={} rhat_threshold={} seed={} exact_max_states={}",
+        config.min_samples,
+        config.max_samples,
+        config.samples_per_state,
+        config.target_mcse,
+        config.num_chains,
+        config.burn_in,
+        config.thin,
+        config.rhat_threshold,
+        config.seed,
+        config.exact_max_states,
+    )?;
+    if config.num_bootstraps > 0 {
+        writeln!(
+            ofile,
+            "# num_bootstraps={} ci_level={}",
+            config.num_bootstraps, config.ci_level,
+        )?;
+    }
+
+    if config.num_permutations > 0 {
+        writeln!(
+            ofile,
+            "# num_permutations={} permute_within_microclusters={}",
+            config.num_permutations, config.permute_within_microclusters,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Queues the regions by decreasing estimated cost, so that the largest regions
+// don't end up running alone at the end.
+fn schedule_regions(
+    links_obj: &links::Links<f32>,
+    regions: &links::IQRegions,
+    config: &configs::GibbsConfig,
+    num_cells: usize,
+) -> Vec<usize> {
+    let mut costs: Vec<(usize, usize, usize)> = regions
+        .groups()
+        .iter()
+        .enumerate()
+        .map(|(index, pivot_feats)| {
+            let num_sec_feats = links_obj.get_from_pivot_hits(pivot_feats).len();
+            let cost = config.region_cost(num_sec_feats, pivot_feats.len(), num_cells);
+            (cost, num_sec_feats * pivot_feats.len(), index)
+        })
+        .collect();
+    costs.sort_by(|a, b| b.cmp(a));
+
+    costs.into_iter().map(|x| x.2).collect()
+}
+
+// (wall time, number of states) of every processed region
+fn log_timings(timings: &mut [(Duration, usize)]) {
+    if timings.is_empty() {
+        return;
+    }
+
+    timings.sort();
+    let total: Duration = timings.iter().map(|x| x.0).sum();
+    let (slowest, slowest_states) = timings[timings.len() - 1];
+    info!(
+        "Region wall times: total {:?}, mean {:?}, median {:?}, max {:?} ({} states)",
+        total,
+        total / timings.len() as u32,
+        timings[timings.len() / 2].0,
+        slowest,
+        slowest_states,
+    );
+}
+
+// (region index, gamma, sec features, pivot features, wall time) sent back by the workers
+type Processed = (usize, Gamma, Vec<usize>, Vec<usize>, Duration);
+
 pub fn callback(
     mm_obj: &multimodal::MultiModalExperiment<f32>,
     links_obj: &links::Links<f32>,
     regions: &links::IQRegions,
     mut ofile: BufWriter<File>,
-    cells: Option<&Vec<usize>>,
+    cells: Option<&[usize]>,
+    config: &configs::GibbsConfig,
+    outputs: &configs::OutputConfig,
 ) -> Result<(), Box<dyn Error>> {
     let num_regions = regions.len();
     let pbar = ProgressBar::new(num_regions as u64);
@@ -198,11 +843,28 @@ pub fn callback(
             .progress_chars("╢▌▌░╟"),
     );
 
-    let num_threads = 10;
+    write_header(&mut ofile, config, links_obj)?;
+    let mut matrix = outputs.mtx_dir.as_ref().map(|_| mtx::GammaMatrix::new());
+    let mut attributions = outputs
+        .attribution_dir
+        .as_ref()
+        .map(|_| mtx::AttributionMatrix::new());
+    let mut summaries = outputs
+        .summary_dir
+        .as_ref()
+        .map(|_| summary::Summaries::new());
+
+    let num_threads = config.num_threads;
     let q = Arc::new(ArrayQueue::<usize>::new(num_regions));
-    (0..num_regions).for_each(|x| q.push(x).unwrap());
+    let num_cells = cells.map_or(mm_obj.num_cells(links_obj.sec_assay()), |x| x.len());
+    let schedule = schedule_regions(links_obj, regions, config, num_cells);
+    let mut positions = vec![0; num_regions];
+    for (position, &index) in schedule.iter().enumerate() {
+        positions[index] = position;
+        q.push(index).unwrap();
+    }
 
-    let (tx, rx) = mpsc::sync_channel(num_threads);
+    let (tx, rx) = mpsc::sync_channel::<Option<Processed>>(num_threads);
     crossbeam::scope(|scope| {
         for _worker in 0..num_threads {
             let tx = tx.clone();
@@ -211,19 +873,59 @@ pub fn callback(
             scope.spawn(move |_| loop {
                 match reader.pop() {
                     Some(index) => {
+                        let start = Instant::now();
                         let pivot_feats = regions.get(index);
                         let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
-                        let gamma = process_region(
+                        let mut gamma = estimate_region(
                             &sec_feats,
                             &pivot_feats,
-                            crate::configs::NUM_SAMPLES,
-                            &links_obj,
-                            &mm_obj,
+                            config,
+                            links_obj,
+                            mm_obj,
                             cells,
+                            None,
                         )
                         .expect("can't process gamma region");
-                        tx.send(Some((gamma, sec_feats, pivot_feats)))
-                            .expect("Could not send mid data!");
+                        if config.num_bootstraps > 0 {
+                            let intervals = bootstrap::process_region(
+                                &sec_feats,
+                                &pivot_feats,
+                                config,
+                                links_obj,
+                                mm_obj,
+                                cells,
+                            )
+                            .expect("can't bootstrap gamma region");
+                            gamma.set_intervals(intervals);
+                        }
+                        if config.num_permutations > 0 {
+                            let pvalues = permutation::process_region(
+                                &sec_feats,
+                                &pivot_feats,
+                                config,
+                                links_obj,
+                                mm_obj,
+                                cells,
+                                &gamma,
+                            )
+                            .expect("can't permute gamma region");
+                            gamma.set_pvalues(pvalues);
+                        }
+                        if !gamma.is_converged() {
+                            warn!(
+                                "Region with {} sec & {} pivot features failed to converge",
+                                sec_feats.len(),
+                                pivot_feats.len()
+                            );
+                        }
+                        tx.send(Some((
+                            index,
+                            gamma,
+                            sec_feats,
+                            pivot_feats,
+                            start.elapsed(),
+                        )))
+                        .expect("Could not send mid data!");
                     }
                     None => {
                         tx.send(None).expect("Could not send end data!");
@@ -233,37 +935,55 @@ pub fn callback(
             });
         }
 
-        let mut num_regions = 0;
+        // regions finish out of order, buffering them until all the regions
+        // scheduled before are written keeps the output in the schedule order,
+        // holding back only the regions overtaking one still running
+        let mut reorder = BTreeMap::new();
+        let mut next_position = 0;
+        let mut num_unconverged = 0;
+        let mut pending = Vec::new();
+        let mut timings = Vec::new();
+        let mut receive = |(index, gamma, sec_feats, pivot_feats, elapsed): Processed| {
+            pbar.inc(1);
+            timings.push((elapsed, sec_feats.len() * pivot_feats.len()));
+            num_unconverged += !gamma.is_converged() as usize;
+            reorder.insert(positions[index], (gamma, sec_feats, pivot_feats));
+
+            while let Some((gamma, sec_feats, pivot_feats)) = reorder.remove(&next_position) {
+                next_position += 1;
+                let region_id = region_id(links_obj, &sec_feats, &pivot_feats);
+                if let Some(matrix) = matrix.as_mut() {
+                    matrix.add(&gamma, &sec_feats, &pivot_feats);
+                }
+                if let Some(attributions) = attributions.as_mut() {
+                    attributions.add(&gamma, &sec_feats, &pivot_feats, links_obj);
+                }
+                if let Some(summaries) = summaries.as_mut() {
+                    summaries.add(&gamma, &sec_feats, &pivot_feats, &region_id);
+                }
+
+                match config.num_permutations > 0 {
+                    // q-values need the p-values of all the regions
+                    true => pending.push((gamma, sec_feats, pivot_feats, region_id)),
+                    false => gamma
+                        .write(&mut ofile, links_obj, &sec_feats, &pivot_feats, &region_id)
+                        .expect("can't write gamma"),
+                };
+            }
+        };
+
         let mut dead_thread_count = 0;
         for out_data in rx.iter() {
             match out_data {
-                Some((gamma, sec_feats, pivot_feats)) => {
-                    pbar.inc(1);
-                    num_regions += 1;
-                    gamma
-                        .write(&mut ofile, mm_obj, &sec_feats, &pivot_feats, num_regions)
-                        .expect("can't write gamma");
-                } // end-Some
+                Some(region) => receive(region),
                 None => {
                     dead_thread_count += 1;
                     if dead_thread_count == num_threads {
                         drop(tx);
 
                         // consume what's remaining
-                        for out_data in rx.iter() {
-                            pbar.inc(1);
-                            num_regions += 1;
-                            out_data.map_or((), |(gamma, sec_feats, pivot_feats)| {
-                                gamma
-                                    .write(
-                                        &mut ofile,
-                                        mm_obj,
-                                        &sec_feats,
-                                        &pivot_feats,
-                                        num_regions,
-                                    )
-                                    .expect("can't write gamma");
-                            });
+                        for region in rx.iter().flatten() {
+                            receive(region);
                         }
 
                         break;
@@ -271,20 +991,59 @@ pub fn callback(
                 } // end-None
             } // end-match
         } // end-for
+        assert!(reorder.is_empty(), "regions left unwritten");
+
+        if num_unconverged > 0 {
+            warn!(
+                "{} / {} regions have R-hat above {}",
+                num_unconverged, num_regions, config.rhat_threshold
+            );
+        }
+        log_timings(&mut timings);
+
+        if !pending.is_empty() {
+            let mut gammas: Vec<&mut Gamma> = pending.iter_mut().map(|x| &mut x.0).collect();
+            permutation::set_qvalues(&mut gammas);
+
+            for (gamma, sec_feats, pivot_feats, region_id) in pending {
+                gamma
+                    .write(&mut ofile, links_obj, &sec_feats, &pivot_feats, &region_id)
+                    .expect("can't write gamma");
+            }
+        }
     })
     .unwrap(); //end crossbeam
 
+    if let (Some(mut matrix), Some(mtx_dir)) = (matrix, &outputs.mtx_dir) {
+        matrix.write(mtx_dir, mm_obj, links_obj)?;
+    }
+    if let (Some(mut attributions), Some(attribution_dir)) =
+        (attributions, &outputs.attribution_dir)
+    {
+        attributions.write(attribution_dir, mm_obj, links_obj)?;
+    }
+    if let (Some(mut summaries), Some(summary_dir)) = (summaries, &outputs.summary_dir) {
+        summaries.write(summary_dir, links_obj)?;
+    }
+
     pbar.finish();
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::cell::Cell;
+    use std::error::Error;
+    use std::fs::File;
+    use std::io::BufWriter;
 
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::configs::GibbsConfig;
+    use crate::fixtures;
     use crate::gibbs;
-    use crate::links::Links;
-    use crate::multimodal::MultiModalExperiment;
+    use crate::gibbs::Walk;
 
     #[test]
     fn test_state() {
@@ -294,30 +1053,331 @@ mod tests {
 
     #[test]
     fn test_gibbs() {
-        let ppath = Path::new("test/pivot");
-        let spath = Path::new("test/sec");
-        let mm_obj =
-            MultiModalExperiment::from_paths(vec![spath.to_path_buf(), ppath.to_path_buf()]);
-
-        let opath = Path::new("test/olaps.tsv");
-        let links_obj = Links::new(&mm_obj, opath.to_path_buf());
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
 
         let pivot_feats = vec![2];
         let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
         assert_eq!(sec_feats, vec![1, 2, 3, 4, 5]);
 
-        let gamma =
-            gibbs::process_region(&sec_feats, &pivot_feats, 100_000, &links_obj, &mm_obj, None)
-                .unwrap();
-        let norm: u32 = gamma._stats().clone().iter().sum();
+        let config = GibbsConfig {
+            min_samples: 100_000,
+            max_samples: 100_000,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+        let gamma = gibbs::process_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            None,
+            None,
+        )
+        .unwrap();
+        let norm: u32 = gamma.stats().clone().iter().sum();
 
-        let exp_gamma = vec![0.223, 0.211, 0.548, 0.018, 0.000];
+        let exp_gamma = [0.223, 0.211, 0.548, 0.018, 0.000];
         let is_reasonable: bool = gamma
-            ._stats()
-            .into_iter()
+            .stats()
+            .iter()
             .enumerate()
             .any(|(index, &x)| ((x as f32 / norm as f32) - exp_gamma[index]).abs() > 1e-2);
 
         assert!(!is_reasonable);
+        assert!(gamma.is_converged());
+        assert_eq!(gamma.rhat().len(), 5);
+        assert!(gamma.ess()[0] > 0.0);
+
+        let (sec_marginals, pivot_marginals) = gamma.marginals();
+        assert_eq!(&sec_marginals, gamma.values());
+        assert!((pivot_marginals[0] - 1.0).abs() < 1e-5);
+    }
+
+    // steps through the states in turn, counting its steps
+    struct CountingWalk {
+        num_steps: Cell<usize>,
+    }
+
+    impl Walk for CountingWalk {
+        type State = usize;
+
+        fn num_states(&self) -> usize {
+            4
+        }
+
+        fn init_state(&self, _rng: &mut StdRng) -> usize {
+            0
+        }
+
+        fn step(&self, state: &mut usize, _rng: &mut StdRng) -> Result<usize, Box<dyn Error>> {
+            self.num_steps.set(self.num_steps.get() + 1);
+            *state = (*state + 1) % 4;
+            Ok(0)
+        }
+
+        fn state_index(&self, state: &usize) -> usize {
+            *state
+        }
+    }
+
+    #[test]
+    fn test_burn_in_thin() {
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+
+        let pivot_feats = vec![0, 1, 3];
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let sampler =
+            gibbs::RegionSampler::new(&sec_feats, &pivot_feats, &links_obj, &mm_obj, None, None);
+        let config = GibbsConfig {
+            min_samples: 1_000,
+            max_samples: 1_000,
+            num_chains: 1,
+            burn_in: 100,
+            thin: 3,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+        let gamma =
+            gibbs::sample_region(&sampler, &config, 7, sec_feats.len(), pivot_feats.len()).unwrap();
+        assert_eq!(gamma.stats().iter().sum::<u32>(), 1_000);
+
+        // the same chain run by hand, stepping over the burn-in and recording
+        // every third state
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut state = sampler.init_state(&mut rng);
+        for _ in 0..100 {
+            sampler.step(&mut state, &mut rng).unwrap();
+        }
+        let mut stats = vec![0_u32; sampler.num_states()];
+        for _ in 0..1_000 {
+            for _ in 0..3 {
+                sampler.step(&mut state, &mut rng).unwrap();
+            }
+            stats[sampler.state_index(&state)] += 1;
+        }
+        assert_eq!(gamma.stats(), &stats);
+
+        // every chain burns in once, then takes thin steps per sample
+        let walk = CountingWalk {
+            num_steps: Cell::new(0),
+        };
+        let config = GibbsConfig {
+            num_chains: 2,
+            ..config
+        };
+        let gamma = gibbs::sample_region(&walk, &config, 7, 2, 2).unwrap();
+        assert_eq!(walk.num_steps.get(), 2 * (100 + 500 * 3));
+        assert_eq!(gamma.stats(), &vec![250, 250, 250, 250]);
+
+        let path = std::env::temp_dir().join("indus_test_burn_in_thin.tsv");
+        {
+            let mut ofile = BufWriter::new(File::create(&path).unwrap());
+            gibbs::write_config(&mut ofile, &config).unwrap();
+        }
+        let header = std::fs::read_to_string(&path).unwrap();
+        assert!(header.starts_with("# min_samples=1000 max_samples=1000 "));
+        assert!(header.contains(" num_chains=2 burn_in=100 thin=3 "));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_seed() {
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+
+        let pivot_feats = vec![0, 1, 3];
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let config = GibbsConfig {
+            min_samples: 10_000,
+            max_samples: 10_000,
+            seed: 42,
+            ..GibbsConfig::default()
+        };
+
+        let first = gibbs::process_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            None,
+            None,
+        )
+        .unwrap();
+        let second = gibbs::process_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(first.stats(), second.stats());
+
+        assert_ne!(
+            gibbs::region_seed(42, &links_obj, &sec_feats, &pivot_feats),
+            gibbs::region_seed(43, &links_obj, &sec_feats, &pivot_feats)
+        );
+
+        // the replicates of every procedure get their own seeds
+        let mut seeds = vec![gibbs::region_seed(42, &links_obj, &sec_feats, &pivot_feats)];
+        for stream in ["bootstrap", "permutation"] {
+            for replicate in 0..3 {
+                seeds.push(gibbs::stream_seed(
+                    42,
+                    stream,
+                    replicate,
+                    &links_obj,
+                    &sec_feats,
+                    &pivot_feats,
+                ));
+            }
+        }
+        let num_seeds = seeds.len();
+        seeds.sort();
+        seeds.dedup();
+        assert_eq!(seeds.len(), num_seeds);
+
+        let region_id = gibbs::region_id(&links_obj, &sec_feats, &pivot_feats);
+        assert_eq!(region_id.len(), 16);
+        assert_eq!(
+            region_id,
+            gibbs::region_id(&links_obj, &sec_feats, &pivot_feats)
+        );
+        assert_ne!(
+            region_id,
+            gibbs::region_id(&links_obj, &links_obj.get_from_pivot_hits(&[2]), &[2])
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+
+        let config = GibbsConfig {
+            min_samples: 400,
+            max_samples: 4_000,
+            samples_per_state: 100,
+            ..GibbsConfig::default()
+        };
+        assert_eq!(config.sample_budget(1), 400);
+        assert_eq!(config.sample_budget(10), 1_000);
+        assert_eq!(config.sample_budget(100), 4_000);
+
+        // the exact solver goes by the cells, the sampler by its samples
+        let config = GibbsConfig {
+            exact_max_states: 4,
+            num_chains: 2,
+            burn_in: 50,
+            thin: 2,
+            ..config
+        };
+        assert_eq!(config.region_cost(2, 2, 5), 40);
+        assert_eq!(config.region_cost(1, 5, 5), 2 * 50 + 500 * 2);
+        assert_eq!(config.region_cost(20, 10, 5), 2 * 50 + 4_000 * 2);
+        let mcse_config = GibbsConfig {
+            target_mcse: Some(1e-3),
+            ..config.clone()
+        };
+        assert_eq!(mcse_config.region_cost(20, 10, 5), 2 * 50 + 400 * 2);
+
+        let pivot_feats = vec![2];
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let config = GibbsConfig {
+            min_samples: 400,
+            max_samples: 40_000,
+            target_mcse: Some(1e-6),
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+        let gamma = gibbs::process_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let norm: u32 = gamma.stats().iter().sum();
+        assert_eq!(norm, 40_000);
+    }
+
+    #[test]
+    fn test_cell_attribution() {
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+
+        let pivot_feats = vec![0, 1, 3];
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let config = GibbsConfig {
+            min_samples: 10_000,
+            max_samples: 10_000,
+            thin: 2,
+            seed: 7,
+            exact_max_states: 0,
+            ..GibbsConfig::default()
+        };
+        let tracked_config = GibbsConfig {
+            cell_attribution: true,
+            ..config.clone()
+        };
+
+        let gamma = gibbs::estimate_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            None,
+            None,
+        )
+        .unwrap();
+        let tracked = gibbs::estimate_region(
+            &sec_feats,
+            &pivot_feats,
+            &tracked_config,
+            &links_obj,
+            &mm_obj,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(gamma.cell_stats().is_none());
+
+        // tracking the cells doesn't change the chain
+        assert_eq!(gamma.stats(), tracked.stats());
+
+        let mut link_stats = vec![0_u32; tracked.stats().len()];
+        for (&(cell_id, index), &count) in tracked.cell_stats().unwrap() {
+            assert!(cell_id < mm_obj.num_cells(0));
+            link_stats[index] += count;
+        }
+        assert_eq!(&link_stats, tracked.stats());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let rng = || StdRng::seed_from_u64(0);
+        let state = || gibbs::State { sec: 0, pivot: 0 };
+
+        let mut mixed = gibbs::Chain::new(state(), rng(), 2, 50);
+        let mut stuck = gibbs::Chain::new(state(), rng(), 2, 50);
+        for index in 0..100 {
+            mixed.record(index % 2);
+            stuck.record(0);
+        }
+
+        let (rhat, ess) = gibbs::diagnostics(&[mixed, stuck]);
+        assert!(rhat[0] > 1.1 && rhat[1] > 1.1);
+        assert!(ess[0] < 200.0);
     }
 }
@@ -3,8 +3,9 @@ use crate::multimodal;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
-use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(PartialEq)]
 pub struct IQRegions {
@@ -20,10 +21,6 @@ impl IQRegions {
         &self.groups
     }
 
-    pub fn _sort(&mut self) {
-        self.groups.sort();
-    }
-
     pub fn get(&self, index: usize) -> Vec<usize> {
         self.groups()[index].clone()
     }
@@ -37,17 +34,189 @@ impl fmt::Debug for IQRegions {
     }
 }
 
+// What to do with the entries of the links, anchors and microcluster files
+// naming features or cells missing from the matrices, or malformed ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Strict,
+    Lenient,
+}
+
+// Which way round the links file lists the pairs, sec then pivot feature for
+// forward. Auto goes with the side matching clearly more of the records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Auto,
+    Forward,
+    Reversed,
+}
+
+// Entries skipped under the lenient policy, as (file, line, kind, value) with
+// the kind being sec, pivot, cell or malformed.
+#[derive(Debug)]
+pub struct SkipReport {
+    policy: Policy,
+    entries: Vec<(String, u64, String, String)>,
+    num_logged: usize,
+}
+
+impl Default for SkipReport {
+    fn default() -> SkipReport {
+        SkipReport::new(Policy::Strict)
+    }
+}
+
+impl SkipReport {
+    pub fn new(policy: Policy) -> SkipReport {
+        SkipReport {
+            policy,
+            entries: Vec::new(),
+            num_logged: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // fails under the strict policy, naming the line of the file
+    fn skip(
+        &mut self,
+        path: &Path,
+        line_num: u64,
+        kind: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let kind = match kind {
+            "malformed" => "malformed line".to_string(),
+            _ => format!("unknown {}", kind),
+        };
+        if self.policy == Policy::Strict {
+            return Err(format!("{}:{}: {} {}", path.display(), line_num, kind, value).into());
+        }
+
+        self.entries.push((
+            path.display().to_string(),
+            line_num,
+            kind,
+            value.to_string(),
+        ));
+        Ok(())
+    }
+
+    // warns about the entries skipped since the last call
+    fn log(&mut self, path: &Path) {
+        let num_skipped = self.entries.len() - self.num_logged;
+        if num_skipped > 0 {
+            warn!("Skipped {} entries of {}", num_skipped, path.display());
+        }
+        self.num_logged = self.entries.len();
+    }
+
+    pub fn write(&self, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut ofile = BufWriter::new(File::create(path)?);
+        writeln!(ofile, "file\tline\treason\tvalue")?;
+        for (file, line_num, kind, value) in self.entries.iter() {
+            writeln!(ofile, "{}\t{}\t{}\t{}", file, line_num, kind, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+// records with the wrong number of columns are reported as malformed, rather
+// than failing in the reader
+fn reader(path: &Path) -> Result<csv::Reader<File>, Box<dyn Error>> {
+    let rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_path(path)
+        .map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+
+    Ok(rdr)
+}
+
+// line number along with the fields of a record
+fn parse_record(record: csv::StringRecord) -> (u64, Vec<String>) {
+    let line_num = record.position().map_or(0, |x| x.line());
+    let values = record.iter().map(|x| x.to_string()).collect();
+    (line_num, values)
+}
+
+// Disjoint sets with union by size and path halving.
+pub struct DisjointSets {
+    parents: Vec<usize>,
+    sizes: Vec<usize>,
+}
+
+impl DisjointSets {
+    pub fn new(num_elems: usize) -> DisjointSets {
+        DisjointSets {
+            parents: (0..num_elems).collect(),
+            sizes: vec![1; num_elems],
+        }
+    }
+
+    pub fn find(&mut self, mut elem: usize) -> usize {
+        while self.parents[elem] != elem {
+            self.parents[elem] = self.parents[self.parents[elem]];
+            elem = self.parents[elem];
+        }
+
+        elem
+    }
+
+    pub fn size(&mut self, elem: usize) -> usize {
+        let root = self.find(elem);
+        self.sizes[root]
+    }
+
+    pub fn union(&mut self, first: usize, second: usize) {
+        let (mut first, mut second) = (self.find(first), self.find(second));
+        if first == second {
+            return;
+        }
+
+        if self.sizes[first] < self.sizes[second] {
+            std::mem::swap(&mut first, &mut second);
+        }
+        self.parents[second] = first;
+        self.sizes[first] += self.sizes[second];
+    }
+}
+
+// sec cell -> (pivot cells, cumulative probabilities of jumping to them)
+pub type Anchors = HashMap<usize, (Vec<usize>, Vec<f32>)>;
+
+// (pivot features per sec feature, sec features per pivot feature, weights)
+type LinkMaps = (
+    HashMap<usize, Vec<usize>>,
+    HashMap<usize, Vec<usize>>,
+    Option<HashMap<(usize, usize), f32>>,
+);
+
+// Links between the features of two modalities, the sec and the pivot one.
 pub struct Links<'a, T> {
-    _mm_obj: &'a multimodal::MultiModalExperiment<T>,
+    mm_obj: &'a multimodal::MultiModalExperiment<T>,
+    sec: usize,
+    pivot: usize,
     to_pivot: HashMap<usize, Vec<usize>>,
     from_pivot: HashMap<usize, Vec<usize>>,
     microclusters: Option<HashMap<String, Vec<usize>>>,
-    anchors: Option<HashMap<usize, (Vec<usize>, Vec<f32>)>>,
+    anchors: Option<Anchors>,
+    weights: Option<HashMap<(usize, usize), f32>>,
 }
 
 impl<'a, T> fmt::Debug for Links<'a, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Found total {} Linked pivot features", self.len())?;
+        write!(
+            f,
+            "Found total {} Linked pivot features ({} -> {})",
+            self.len(),
+            self.mm_obj.names()[self.sec],
+            self.mm_obj.names()[self.pivot]
+        )?;
         if let Some(microclusters) = &self.microclusters {
             write!(f, ", {} micorclusters", microclusters.len())?;
         }
@@ -56,123 +225,130 @@ impl<'a, T> fmt::Debug for Links<'a, T> {
             write!(f, " & {} pivot anchors", anchors.len())?;
         }
 
+        if self.has_weights() {
+            write!(f, ", weighted")?;
+        }
+
         Ok(())
     }
 }
 
 impl<'a, T> Links<'a, T> {
-    pub fn new(mm_obj: &multimodal::MultiModalExperiment<T>, links_file_path: PathBuf) -> Links<T> {
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .delimiter(b'\t')
-            .from_path(links_file_path)
-            .expect("can't read the links file");
-
-        let (to_pivot, from_pivot) = Links::get_links(&mut rdr, &mm_obj);
-        Links {
-            _mm_obj: mm_obj,
+    // links of the sec assay to the pivot assay, read from a file of feature
+    // name pairs with an optional third column of prior link weights
+    pub fn new(
+        mm_obj: &'a multimodal::MultiModalExperiment<T>,
+        links_file_path: PathBuf,
+        sec: usize,
+        pivot: usize,
+        orientation: Orientation,
+        report: &mut SkipReport,
+    ) -> Result<Links<'a, T>, Box<dyn Error>> {
+        assert!(sec != pivot, "can't link a modality to itself");
+        let mut rdr = reader(&links_file_path)?;
+
+        let (to_pivot, from_pivot, weights) = Links::get_links(
+            &mut rdr,
+            &links_file_path,
+            mm_obj,
+            sec,
+            pivot,
+            orientation,
+            report,
+        )?;
+        Ok(Links {
+            mm_obj,
+            sec,
+            pivot,
             from_pivot,
             to_pivot,
             microclusters: None,
             anchors: None,
-        }
+            weights,
+        })
     }
 
-    pub fn new_with_microclusters(
-        mm_obj: &multimodal::MultiModalExperiment<T>,
-        links_file_path: PathBuf,
+    pub fn read_microclusters(
+        &mut self,
         microclusters_file_path: PathBuf,
-    ) -> Links<T> {
-        let mut links = Links::new(&mm_obj, links_file_path);
-
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .delimiter(b'\t')
-            .from_path(microclusters_file_path)
-            .expect("can't read the microcluster file");
-
-        let clusters = Links::get_microclusters(&mut rdr, &mm_obj);
-        links.set_microclusters(clusters);
-
-        links
-    }
-
-    pub fn new_with_anchors(
-        mm_obj: &multimodal::MultiModalExperiment<T>,
-        links_file_path: PathBuf,
-        anchors_file_path: PathBuf,
-    ) -> Links<T> {
-        let mut links = Links::new(&mm_obj, links_file_path);
-
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .delimiter(b'\t')
-            .from_path(anchors_file_path)
-            .expect("can't read the microcluster file");
-
-        let clusters = Links::get_anchors(&mut rdr, &mm_obj);
-        links.set_anchors(clusters);
-
-        links
+        report: &mut SkipReport,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut rdr = reader(&microclusters_file_path)?;
+
+        let clusters = Links::get_microclusters(
+            &mut rdr,
+            &microclusters_file_path,
+            self.mm_obj,
+            self.sec,
+            report,
+        )?;
+        self.set_microclusters(clusters);
+        Ok(())
     }
 
-    pub fn new_with_microclusters_and_anchors(
-        mm_obj: &multimodal::MultiModalExperiment<T>,
-        links_file_path: PathBuf,
-        microclusters_file_path: PathBuf,
+    pub fn read_anchors(
+        &mut self,
         anchors_file_path: PathBuf,
-    ) -> Links<T> {
-        let mut links = Links::new(&mm_obj, links_file_path);
-
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .delimiter(b'\t')
-            .from_path(microclusters_file_path)
-            .expect("can't read the microcluster file");
-
-        let clusters = Links::get_microclusters(&mut rdr, &mm_obj);
-        links.set_microclusters(clusters);
-
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .delimiter(b'\t')
-            .from_path(anchors_file_path)
-            .expect("can't read the microcluster file");
-
-        let anchors = Links::get_anchors(&mut rdr, &mm_obj);
-        links.set_anchors(anchors);
-
-        links
+        report: &mut SkipReport,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut rdr = reader(&anchors_file_path)?;
+
+        let anchors = Links::get_anchors(
+            &mut rdr,
+            &anchors_file_path,
+            self.mm_obj,
+            self.sec,
+            self.pivot,
+            report,
+        )?;
+        self.set_anchors(anchors);
+        Ok(())
     }
 
+    // anchors map the cells of the sec modality to the cells of the pivot one,
+    // each resolved against the barcodes of its own modality, on the keys the
+    // modalities were reconciled on
     fn get_anchors(
-        rdr: &mut csv::Reader<std::fs::File>,
+        rdr: &mut csv::Reader<File>,
+        path: &Path,
         mm_obj: &multimodal::MultiModalExperiment<T>,
-    ) -> HashMap<usize, (Vec<usize>, Vec<f32>)> {
-        let mut anchors = HashMap::<usize, (Vec<usize>, Vec<f32>)>::new();
+        sec: usize,
+        pivot: usize,
+        report: &mut SkipReport,
+    ) -> Result<Anchors, Box<dyn Error>> {
+        let mut anchors = Anchors::new();
         {
-            let all_cells = mm_obj.cells();
-            let mut cells_string_to_index = HashMap::<String, usize>::new();
-            for (index, cell_string) in all_cells.into_iter().enumerate() {
-                cells_string_to_index.insert(cell_string.to_owned(), index);
-            }
+            let (sec_cells, pivot_cells) = (mm_obj.cell_keys(sec), mm_obj.cell_keys(pivot));
 
             for line in rdr.records() {
-                let record = line.unwrap();
-                let values: Vec<String> =
-                    record.into_iter().flat_map(str::parse::<String>).collect();
-                assert_eq!(values.len(), 3);
-
-                // todo handle cases when value not in the input matrix
-                let sec_cb_index = *cells_string_to_index.get(&values[0]).unwrap();
-                let pivot_cb_index = *cells_string_to_index.get(&values[1]).unwrap();
-                let probability = values[2].parse::<f32>().unwrap();
-
-                let val = anchors
-                    .entry(sec_cb_index)
-                    .or_insert((Vec::new(), Vec::new()));
-                val.0.push(pivot_cb_index);
-                val.1.push(probability);
+                let (line_num, values) = parse_record(line?);
+                let probability = values.get(2).and_then(|x| x.parse::<f32>().ok());
+                let probability = match (values.len(), probability) {
+                    (3, Some(probability)) if probability.is_finite() && probability >= 0.0 => {
+                        probability
+                    }
+                    _ => {
+                        report.skip(path, line_num, "malformed", &values.join(" "))?;
+                        continue;
+                    }
+                };
+
+                let sec_cb_index = sec_cells.get(&mm_obj.barcode_key(&values[0]));
+                let pivot_cb_index = pivot_cells.get(&mm_obj.barcode_key(&values[1]));
+                for (index, name) in [(sec_cb_index, &values[0]), (pivot_cb_index, &values[1])] {
+                    if index.is_none() {
+                        report.skip(path, line_num, "cell", name)?;
+                    }
+                }
+
+                if let (Some(&sec_cb_index), Some(&pivot_cb_index)) = (sec_cb_index, pivot_cb_index)
+                {
+                    let val = anchors
+                        .entry(sec_cb_index)
+                        .or_insert((Vec::new(), Vec::new()));
+                    val.0.push(pivot_cb_index);
+                    val.1.push(probability);
+                }
             }
         } // end populating maps
 
@@ -183,7 +359,7 @@ impl<'a, T> Links<'a, T> {
 
             let norm: f32 = val.1.iter().sum();
             let cum_sum_iter = val.1.iter_mut().scan(0.0_f32, |cusum, x| {
-                *cusum = *cusum + (*x / norm);
+                *cusum += *x / norm;
                 Some(*cusum)
             });
 
@@ -191,77 +367,199 @@ impl<'a, T> Links<'a, T> {
             assert!(val.0.len() == val.1.len());
         }
 
-        anchors
+        report.log(path);
+        Ok(anchors)
     }
 
+    // microclusters group the cells of the sec modality
     fn get_microclusters(
-        rdr: &mut csv::Reader<std::fs::File>,
+        rdr: &mut csv::Reader<File>,
+        path: &Path,
         mm_obj: &multimodal::MultiModalExperiment<T>,
-    ) -> HashMap<String, Vec<usize>> {
+        sec: usize,
+        report: &mut SkipReport,
+    ) -> Result<HashMap<String, Vec<usize>>, Box<dyn Error>> {
         let mut clusters = HashMap::<String, Vec<usize>>::new();
         {
-            let all_cells = mm_obj.cells();
-            let mut cells_string_to_index = HashMap::<String, usize>::new();
-            for (index, cell_string) in all_cells.into_iter().enumerate() {
-                cells_string_to_index.insert(cell_string.to_owned(), index);
-            }
+            let cells_string_to_index = mm_obj.cell_keys(sec);
 
             for line in rdr.records() {
-                let record = line.unwrap();
-                let values: Vec<String> =
-                    record.into_iter().flat_map(str::parse::<String>).collect();
-                assert_eq!(values.len(), 2);
+                let (line_num, values) = parse_record(line?);
+                if values.len() != 2 {
+                    report.skip(path, line_num, "malformed", &values.join(" "))?;
+                    continue;
+                }
 
-                // todo handle cases when value not in the input matrix
-                let cb_index = *cells_string_to_index.get(&values[0]).unwrap();
+                let cb_index = match cells_string_to_index.get(&mm_obj.barcode_key(&values[0])) {
+                    Some(&cb_index) => cb_index,
+                    None => {
+                        report.skip(path, line_num, "cell", &values[0])?;
+                        continue;
+                    }
+                };
                 let cl_id = values[1].clone();
 
-                clusters.entry(cl_id).or_insert(Vec::new()).push(cb_index);
+                clusters.entry(cl_id).or_default().push(cb_index);
             }
         } // end populating maps
 
-        clusters
+        report.log(path);
+        Ok(clusters)
     }
 
     fn get_links(
-        rdr: &mut csv::Reader<std::fs::File>,
+        rdr: &mut csv::Reader<File>,
+        path: &Path,
         mm_obj: &multimodal::MultiModalExperiment<T>,
-    ) -> (HashMap<usize, Vec<usize>>, HashMap<usize, Vec<usize>>) {
+        sec: usize,
+        pivot: usize,
+        orientation: Orientation,
+        report: &mut SkipReport,
+    ) -> Result<LinkMaps, Box<dyn Error>> {
         let mut to_pivot = HashMap::<usize, Vec<usize>>::new();
         let mut from_pivot = HashMap::<usize, Vec<usize>>::new();
+        let mut weights = HashMap::<(usize, usize), f32>::new();
         {
             let all_features = mm_obj.features();
             let mut feature_string_to_index = Vec::<HashMap<String, usize>>::new();
             for features in all_features {
                 let mut indexing = HashMap::<String, usize>::new();
-                for (index, feature) in features.into_iter().enumerate() {
+                for (index, feature) in features.iter().enumerate() {
                     indexing.insert(feature.to_owned(), index);
                 }
                 feature_string_to_index.push(indexing);
             }
 
+            let mut records = Vec::new();
             for line in rdr.records() {
-                let record = line.unwrap();
-                let values: Vec<String> =
-                    record.into_iter().flat_map(str::parse::<String>).collect();
-                assert_eq!(values.len(), 2);
-
-                // todo handle cases when value not in the input matrix
-                let sec_index = *feature_string_to_index[0].get(&values[0]).unwrap();
-                let pivot_index = *feature_string_to_index[1].get(&values[1]).unwrap();
-
-                to_pivot
-                    .entry(sec_index)
-                    .or_insert(Vec::new())
-                    .push(pivot_index);
-                from_pivot
-                    .entry(pivot_index)
-                    .or_insert(Vec::new())
-                    .push(sec_index);
+                let (line_num, values) = parse_record(line?);
+                // link weights have to be non-negative
+                let is_valid = match (values.len(), values.get(2)) {
+                    (2, None) => true,
+                    (3, Some(weight)) => {
+                        matches!(weight.parse::<f32>(), Ok(x) if x.is_finite() && x >= 0.0)
+                    }
+                    _ => false,
+                };
+                match is_valid {
+                    true => records.push((line_num, values)),
+                    false => report.skip(path, line_num, "malformed", &values.join(" "))?,
+                };
+            }
+
+            // the links file can list the pairs either way round, guessing it
+            // fails unless one way matches at least twice the records of the
+            // other, e.g. when the same features make up both modalities
+            let (sec_map, pivot_map) = (
+                &feature_string_to_index[sec],
+                &feature_string_to_index[pivot],
+            );
+            let matches = |first: &HashMap<String, usize>, second: &HashMap<String, usize>| {
+                records
+                    .iter()
+                    .filter(|(_, x)| first.contains_key(&x[0]) && second.contains_key(&x[1]))
+                    .count()
+            };
+            let (num_forward, num_reversed) =
+                (matches(sec_map, pivot_map), matches(pivot_map, sec_map));
+            let is_reversed = match orientation {
+                Orientation::Forward => false,
+                Orientation::Reversed => true,
+                Orientation::Auto => {
+                    if num_forward.max(num_reversed) < 2 * num_forward.min(num_reversed) {
+                        return Err(format!(
+                            "can't tell the orientation of {}, {} records match sec then pivot features and {} the other way round",
+                            path.display(),
+                            num_forward,
+                            num_reversed
+                        )
+                        .into());
+                    }
+                    num_reversed > num_forward
+                }
+            };
+            info!(
+                "Links file {} lists {} features first, {} of {} records match that way",
+                path.display(),
+                match is_reversed {
+                    true => "pivot",
+                    false => "sec",
+                },
+                match is_reversed {
+                    true => num_reversed,
+                    false => num_forward,
+                },
+                records.len()
+            );
+
+            for (line_num, values) in records {
+                let (sec_name, pivot_name) = match is_reversed {
+                    true => (&values[1], &values[0]),
+                    false => (&values[0], &values[1]),
+                };
+
+                let sec_index = sec_map.get(sec_name);
+                let pivot_index = pivot_map.get(pivot_name);
+                if sec_index.is_none() {
+                    report.skip(path, line_num, "sec", sec_name)?;
+                }
+                if pivot_index.is_none() {
+                    report.skip(path, line_num, "pivot", pivot_name)?;
+                }
+                let (sec_index, pivot_index) = match (sec_index, pivot_index) {
+                    (Some(&sec_index), Some(&pivot_index)) => (sec_index, pivot_index),
+                    _ => continue,
+                };
+
+                if let Some(weight) = values.get(2) {
+                    weights.insert((sec_index, pivot_index), weight.parse::<f32>()?);
+                }
+
+                to_pivot.entry(sec_index).or_default().push(pivot_index);
+                from_pivot.entry(pivot_index).or_default().push(sec_index);
             }
         } // end populating maps
 
-        (to_pivot, from_pivot)
+        report.log(path);
+
+        // links without a weight of their own count as 1 when others have one
+        match weights.is_empty() {
+            true => Ok((to_pivot, from_pivot, None)),
+            false => Ok((to_pivot, from_pivot, Some(weights))),
+        }
+    }
+
+    pub fn has_weights(&self) -> bool {
+        self.weights.is_some()
+    }
+
+    // prior weight of the link between the sec and the pivot feature
+    pub fn weight(&self, sec_feat: usize, pivot_feat: usize) -> f32 {
+        match &self.weights {
+            Some(weights) => *weights.get(&(sec_feat, pivot_feat)).unwrap_or(&1.0),
+            None => 1.0,
+        }
+    }
+
+    pub fn sec_assay(&self) -> usize {
+        self.sec
+    }
+
+    pub fn pivot_assay(&self) -> usize {
+        self.pivot
+    }
+
+    pub fn feature_string(&self, is_pivot: bool, index: usize) -> &str {
+        match is_pivot {
+            true => self.mm_obj.get_feature_string(self.pivot, index),
+            false => self.mm_obj.get_feature_string(self.sec, index),
+        }
+    }
+
+    // sec_pivot names of the linked modalities
+    pub fn name(&self) -> String {
+        let names = self.mm_obj.names();
+        format!("{}_{}", names[self.sec], names[self.pivot])
     }
 
     pub fn set_microclusters(&mut self, clusters: HashMap<String, Vec<usize>>) {
@@ -276,6 +574,16 @@ impl<'a, T> Links<'a, T> {
         self.anchors.is_some()
     }
 
+    // sec cells with at least one anchor, in order
+    pub fn anchored_cells(&self) -> Vec<usize> {
+        let mut cells: Vec<usize> = match &self.anchors {
+            Some(anchors) => anchors.keys().cloned().collect(),
+            None => Vec::new(),
+        };
+        cells.sort();
+        cells
+    }
+
     pub fn get_anchor(&self, cell_id: usize) -> Option<&(Vec<usize>, Vec<f32>)> {
         match &self.anchors {
             Some(anchors) => anchors.get(&cell_id),
@@ -287,7 +595,7 @@ impl<'a, T> Links<'a, T> {
         self.microclusters.as_ref()
     }
 
-    pub fn set_anchors(&mut self, anchors: HashMap<usize, (Vec<usize>, Vec<f32>)>) {
+    pub fn set_anchors(&mut self, anchors: Anchors) {
         self.anchors = Some(anchors);
     }
 
@@ -311,18 +619,38 @@ impl<'a, T> Links<'a, T> {
         }
     }
 
+    // all the cells jump_cell_id can pick along with their probabilities
+    pub fn jump_probabilities(&self, sec_cell_id: usize) -> Vec<(usize, f32)> {
+        match self.get_anchor(sec_cell_id) {
+            None => vec![(sec_cell_id, 1.0)],
+            Some((cell_ids, _)) if cell_ids.len() == 1 => vec![(cell_ids[0], 1.0)],
+            Some((cell_ids, probs)) => {
+                let mut prev = 0.0_f32;
+                let mut jumps: Vec<(usize, f32)> = Vec::with_capacity(cell_ids.len());
+                for (&cell_id, &cum_prob) in cell_ids.iter().zip(probs.iter()) {
+                    jumps.push((cell_id, cum_prob - prev));
+                    prev = cum_prob;
+                }
+
+                // the last anchor absorbs any rounding in the cumulative sum
+                jumps.last_mut().unwrap().1 += 1.0 - prev;
+                jumps
+            }
+        }
+    }
+
     pub fn get_pivot_features(&self) -> HashSet<usize> {
-        self.from_pivot.keys().into_iter().map(|x| *x).collect()
+        self.from_pivot.keys().copied().collect()
     }
 
-    pub fn _get_sec_features(&self) -> HashSet<usize> {
-        self.to_pivot.keys().into_iter().map(|x| *x).collect()
+    pub fn get_sec_features(&self) -> HashSet<usize> {
+        self.to_pivot.keys().copied().collect()
     }
 
-    pub fn get_to_pivot_hits(&self, query: &Vec<usize>) -> Vec<usize> {
+    pub fn _get_to_pivot_hits(&self, query: &[usize]) -> Vec<usize> {
         let mut all_hits = Vec::new();
         for elem in query {
-            let mut elem_hits = self.to_pivot.get(&elem).unwrap().clone();
+            let mut elem_hits = self.to_pivot.get(elem).unwrap().clone();
             all_hits.append(&mut elem_hits);
         }
 
@@ -331,10 +659,10 @@ impl<'a, T> Links<'a, T> {
         all_hits
     }
 
-    pub fn get_from_pivot_hits(&self, query: &Vec<usize>) -> Vec<usize> {
+    pub fn get_from_pivot_hits(&self, query: &[usize]) -> Vec<usize> {
         let mut all_hits = Vec::new();
         for elem in query {
-            let mut elem_hits = self.from_pivot.get(&elem).unwrap().clone();
+            let mut elem_hits = self.from_pivot.get(elem).unwrap().clone();
             all_hits.append(&mut elem_hits);
         }
 
@@ -343,6 +671,74 @@ impl<'a, T> Links<'a, T> {
         all_hits
     }
 
+    // Links of a region in terms of positions within its feature lists, i.e.
+    // (linked sec positions per pivot position, linked pivot positions per sec position)
+    pub fn local_links(
+        &self,
+        sec_feats: &[usize],
+        pivot_feats: &[usize],
+    ) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let sec_lookup: HashMap<usize, usize> = sec_feats
+            .iter()
+            .enumerate()
+            .map(|(index, &feat)| (feat, index))
+            .collect();
+        let pivot_lookup: HashMap<usize, usize> = pivot_feats
+            .iter()
+            .enumerate()
+            .map(|(index, &feat)| (feat, index))
+            .collect();
+
+        let sec_indices = pivot_feats
+            .iter()
+            .map(|&feat| {
+                self.entry_from_pivot(feat)
+                    .iter()
+                    .map(|x| sec_lookup[x])
+                    .collect()
+            })
+            .collect();
+        let pivot_indices = sec_feats
+            .iter()
+            .map(|&feat| {
+                self.entry_to_pivot(feat)
+                    .iter()
+                    .map(|x| pivot_lookup[x])
+                    .collect()
+            })
+            .collect();
+
+        (sec_indices, pivot_indices)
+    }
+
+    // prior weights of the links listed by local_links, in the same layout
+    pub fn local_weights(
+        &self,
+        sec_feats: &[usize],
+        pivot_feats: &[usize],
+    ) -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+        let sec_weights = pivot_feats
+            .iter()
+            .map(|&pivot| {
+                self.entry_from_pivot(pivot)
+                    .iter()
+                    .map(|&sec| self.weight(sec, pivot))
+                    .collect()
+            })
+            .collect();
+        let pivot_weights = sec_feats
+            .iter()
+            .map(|&sec| {
+                self.entry_to_pivot(sec)
+                    .iter()
+                    .map(|&pivot| self.weight(sec, pivot))
+                    .collect()
+            })
+            .collect();
+
+        (sec_weights, pivot_weights)
+    }
+
     pub fn entry_to_pivot(&self, query: usize) -> &Vec<usize> {
         self.to_pivot.get(&query).unwrap()
     }
@@ -351,50 +747,142 @@ impl<'a, T> Links<'a, T> {
         self.from_pivot.get(&query).unwrap()
     }
 
-    // TODO: Currently it's very ugly looking code, needs more work.
+    // sec features take the first nodes of the link graph, pivot ones the rest
+    fn link_graph(&self) -> (DisjointSets, usize) {
+        let num_secs = self.mm_obj.features()[self.sec].len();
+        let num_pivots = self.mm_obj.features()[self.pivot].len();
+        (DisjointSets::new(num_secs + num_pivots), num_secs)
+    }
+
+    // connected components of the bipartite link graph, as the groups of
+    // their pivot features
     pub fn extract_iqr(&self) -> Result<IQRegions, Box<dyn Error>> {
-        let mut groups = Vec::new();
-        let mut pivot_features = self.get_pivot_features();
-        while pivot_features.len() > 0 {
-            let elem = *pivot_features.iter().nth(0).unwrap();
-            let group = self.extract_region(elem);
-            let group_set = HashSet::from_iter(group.iter().cloned());
+        let (mut sets, num_secs) = self.link_graph();
+        for (&sec, pivots) in self.to_pivot.iter() {
+            for &pivot in pivots {
+                sets.union(sec, num_secs + pivot);
+            }
+        }
 
-            groups.push(group);
-            pivot_features = pivot_features.difference(&group_set).map(|x| *x).collect();
+        let mut components = HashMap::<usize, Vec<usize>>::new();
+        for pivot in self.get_pivot_features() {
+            let root = sets.find(num_secs + pivot);
+            components.entry(root).or_default().push(pivot);
         }
 
+        // ordering the groups keeps the region indices independent of the hash
+        // map iteration order
+        let mut groups: Vec<Vec<usize>> = components.into_iter().map(|x| x.1).collect();
+        for group in groups.iter_mut() {
+            group.sort();
+        }
+        groups.sort();
         Ok(IQRegions { groups })
     }
 
-    fn extract_region(&self, seed: usize) -> Vec<usize> {
-        let mut pivot_hits = vec![seed];
-        let mut num_pivot_hits = 1;
-        let mut num_sec_hits = 0;
+    // Splits the regions with more than max_size sec and pivot features by
+    // dropping their weakest links, i.e. the ones with the lowest weight, which
+    // for distance based weights are the most distal ones. Links are added back
+    // strongest first as long as they don't grow a region past max_size, with
+    // ties going to the links between the less connected features.
+    pub fn limit_region_size(&mut self, max_size: usize) -> usize {
+        assert!(max_size > 1, "regions need room for at least one link");
+
+        let (mut sets, num_secs) = self.link_graph();
+        let mut links = Vec::new();
+        for (&sec, pivots) in self.to_pivot.iter() {
+            for &pivot in pivots {
+                sets.union(sec, num_secs + pivot);
+                links.push((sec, pivot));
+            }
+        }
 
-        let mut pivot_change = true;
-        let mut sec_change = true;
+        links.retain(|&(sec, _)| sets.size(sec) > max_size);
+        if links.is_empty() {
+            return 0;
+        }
 
-        while pivot_change || sec_change {
-            let sec_hits = self.get_from_pivot_hits(&pivot_hits);
-            pivot_hits = self.get_to_pivot_hits(&sec_hits);
+        let strength = |&(sec, pivot): &(usize, usize)| {
+            let num_partners = self.to_pivot[&sec].len() + self.from_pivot[&pivot].len();
+            (self.weight(sec, pivot), num_partners)
+        };
+        links.sort_by(|a, b| {
+            let (a_weight, a_partners) = strength(a);
+            let (b_weight, b_partners) = strength(b);
+            b_weight
+                .partial_cmp(&a_weight)
+                .unwrap()
+                .then(a_partners.cmp(&b_partners))
+                .then(a.cmp(b))
+        });
+
+        let (mut split_sets, _) = self.link_graph();
+        let mut dropped = Vec::new();
+        for &(sec, pivot) in links.iter() {
+            let (sec_root, pivot_root) = (split_sets.find(sec), split_sets.find(num_secs + pivot));
+            if sec_root == pivot_root {
+                continue;
+            }
 
-            if pivot_hits.len() != num_pivot_hits {
-                num_pivot_hits = pivot_hits.len();
-                pivot_change = true;
-            } else {
-                pivot_change = false;
+            match split_sets.size(sec_root) + split_sets.size(pivot_root) > max_size {
+                true => dropped.push((sec, pivot)),
+                false => {
+                    split_sets.union(sec_root, pivot_root);
+                }
             }
+        }
+
+        // (size, parts, dropped links) of every split region
+        let mut splits = HashMap::<usize, (usize, HashSet<usize>, usize)>::new();
+        for &(sec, pivot) in links.iter() {
+            let root = sets.find(sec);
+            let size = sets.size(root);
+            let split = splits.entry(root).or_insert((size, HashSet::new(), 0));
+            split.1.insert(split_sets.find(sec));
+            split.1.insert(split_sets.find(num_secs + pivot));
+        }
+        for &(sec, pivot) in dropped.iter() {
+            debug!(
+                "Dropping the link {} - {} with weight {}",
+                self.feature_string(false, sec),
+                self.feature_string(true, pivot),
+                self.weight(sec, pivot)
+            );
+            splits.get_mut(&sets.find(sec)).unwrap().2 += 1;
+            self.remove_link(sec, pivot);
+        }
+
+        let mut splits: Vec<(usize, usize, usize)> = splits
+            .into_iter()
+            .map(|(_, (size, parts, num_dropped))| (size, parts.len(), num_dropped))
+            .collect();
+        splits.sort();
+        for (size, num_parts, num_dropped) in splits.into_iter().rev() {
+            info!(
+                "Split a region of {} features into {} by dropping {} links",
+                size, num_parts, num_dropped
+            );
+        }
 
-            if sec_hits.len() != num_sec_hits {
-                num_sec_hits = sec_hits.len();
-                sec_change = true;
-            } else {
-                sec_change = false;
+        dropped.len()
+    }
+
+    // features left without any link leave the link maps altogether
+    fn remove_link(&mut self, sec: usize, pivot: usize) {
+        for (map, key, value) in [
+            (&mut self.to_pivot, sec, pivot),
+            (&mut self.from_pivot, pivot, sec),
+        ] {
+            let partners = map.get_mut(&key).unwrap();
+            partners.retain(|&x| x != value);
+            if partners.is_empty() {
+                map.remove(&key);
             }
         }
 
-        pivot_hits
+        if let Some(weights) = self.weights.as_mut() {
+            weights.remove(&(sec, pivot));
+        }
     }
 }
 
@@ -404,19 +892,14 @@ mod tests {
     use std::iter::FromIterator;
     use std::path::Path;
 
-    use crate::links::IQRegions;
-    use crate::links::Links;
-    use crate::multimodal::MultiModalExperiment;
+    use crate::fixtures;
+    use crate::links::{Links, Orientation, Policy, SkipReport};
+    use crate::multimodal::{BarcodePolicy, MultiModalExperiment};
 
     #[test]
     fn test_links() {
-        let ppath = Path::new("test/pivot");
-        let spath = Path::new("test/sec");
-        let mm_obj =
-            MultiModalExperiment::from_paths(vec![spath.to_path_buf(), ppath.to_path_buf()]);
-
-        let opath = Path::new("test/olaps.tsv");
-        let links_obj = Links::new(&mm_obj, opath.to_path_buf());
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
 
         assert_eq!(links_obj.len(), 4);
         assert_eq!(
@@ -424,20 +907,353 @@ mod tests {
             HashSet::from_iter(vec![0, 1, 2, 3])
         );
         assert_eq!(
-            links_obj._get_sec_features(),
+            links_obj.get_sec_features(),
             HashSet::from_iter(vec![6, 2, 1, 7, 3, 5, 4, 0])
         );
         assert_eq!(links_obj.entry_to_pivot(7), &vec![3, 1]);
         assert_eq!(links_obj.entry_from_pivot(1), &vec![0, 7]);
-        assert_eq!(links_obj.get_to_pivot_hits(&vec![0, 3]), vec![0, 1, 2]);
-        assert_eq!(links_obj.get_from_pivot_hits(&vec![0, 3]), vec![0, 6, 7]);
-        assert_eq!(links_obj.extract_region(0), vec![0, 1, 3]);
+        assert_eq!(links_obj._get_to_pivot_hits(&[0, 3]), vec![0, 1, 2]);
+        assert_eq!(links_obj.get_from_pivot_hits(&[0, 3]), vec![0, 6, 7]);
+        assert_eq!(
+            links_obj.local_links(&[0, 6, 7], &[0, 1, 3]),
+            (
+                vec![vec![0], vec![0, 2], vec![1, 2]],
+                vec![vec![0, 1], vec![2], vec![2, 1]]
+            )
+        );
         assert_eq!(
-            links_obj.extract_iqr().unwrap()._sort(),
-            IQRegions {
-                groups: vec![vec![0, 1, 3], vec![2]]
+            links_obj.extract_iqr().unwrap().groups(),
+            &vec![vec![0, 1, 3], vec![2]]
+        );
+    }
+
+    #[test]
+    fn test_chain_links() {
+        let ppath = Path::new("test/pivot");
+        let spath = Path::new("test/sec");
+        let mm_obj = MultiModalExperiment::from_named_paths(
+            vec![
+                spath.to_path_buf(),
+                ppath.to_path_buf(),
+                ppath.to_path_buf(),
+            ],
+            vec!["atac".to_string(), "rna".to_string(), "adt".to_string()],
+        );
+
+        let first = fixtures::links(&mm_obj, "test/olaps.tsv");
+        let cpath = Path::new("test/chain.tsv");
+        let second = Links::new(
+            &mm_obj,
+            cpath.to_path_buf(),
+            1,
+            2,
+            Orientation::Forward,
+            &mut SkipReport::default(),
+        )
+        .unwrap();
+
+        assert_eq!(first.name(), "atac_rna");
+        assert_eq!(second.name(), "rna_adt");
+        assert_eq!((second.sec_assay(), second.pivot_assay()), (1, 2));
+        assert_eq!(second.feature_string(false, 3), "OR4F16");
+        assert_eq!(second.entry_from_pivot(1), &vec![0, 1]);
+        assert_eq!(
+            second.extract_iqr().unwrap().groups(),
+            &vec![vec![0], vec![1], vec![2]]
+        );
+
+        // the same genes on both sides match either way round
+        let err = Links::new(
+            &mm_obj,
+            cpath.to_path_buf(),
+            1,
+            2,
+            Orientation::Auto,
+            &mut SkipReport::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("can't tell the orientation"));
+
+        let reversed = Links::new(
+            &mm_obj,
+            cpath.to_path_buf(),
+            1,
+            2,
+            Orientation::Reversed,
+            &mut SkipReport::default(),
+        )
+        .unwrap();
+        assert_eq!(reversed.entry_from_pivot(0), &vec![1]);
+        assert_eq!(reversed.entry_from_pivot(3), &vec![0]);
+    }
+
+    #[test]
+    fn test_reversed_links() {
+        let mm_obj = fixtures::experiment();
+
+        // peaks as the pivot with the links file still listing peaks first
+        let opath = Path::new("test/olaps.tsv");
+        let forward = fixtures::links(&mm_obj, "test/olaps.tsv");
+        let reversed = Links::new(
+            &mm_obj,
+            opath.to_path_buf(),
+            1,
+            0,
+            Orientation::Auto,
+            &mut SkipReport::default(),
+        )
+        .unwrap();
+
+        assert_eq!(reversed.len(), 8);
+        assert_eq!(reversed.feature_string(true, 7), "chr1-633995-634215");
+        for feature in 0..4 {
+            assert_eq!(
+                reversed.entry_to_pivot(feature),
+                forward.entry_from_pivot(feature)
+            );
+        }
+        assert_eq!(reversed.extract_iqr().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_weighted_links() {
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+        assert!(!links_obj.has_weights());
+
+        // links without a weight column default to a weight of one
+        let links_obj = fixtures::links(&mm_obj, "test/olaps_weighted.tsv");
+        assert!(links_obj.has_weights());
+        assert_eq!(links_obj.len(), 4);
+        assert_eq!(links_obj.weight(0, 0), 0.0);
+        assert_eq!(links_obj.weight(0, 1), 2.5);
+        assert_eq!(links_obj.weight(1, 2), 1.0);
+
+        let (sec_weights, pivot_weights) = links_obj.local_weights(&[0], &[0, 1]);
+        assert_eq!(sec_weights, vec![vec![0.0], vec![2.5, 0.5]]);
+        assert_eq!(pivot_weights, vec![vec![0.0, 2.5]]);
+    }
+
+    #[test]
+    fn test_skip_report() {
+        let mm_obj = fixtures::experiment();
+
+        let opath = Path::new("test/olaps_unknown.tsv");
+        let err = Links::new(
+            &mm_obj,
+            opath.to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut SkipReport::default(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "test/olaps_unknown.tsv:4: malformed line chr1-180794-181148 AP006222.2 heavy"
+        );
+
+        let mut report = SkipReport::new(Policy::Lenient);
+        let mut links_obj = Links::new(
+            &mm_obj,
+            opath.to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(links_obj.len(), 2);
+        assert_eq!(links_obj.entry_from_pivot(0), &vec![0]);
+        let skipped: Vec<(u64, &str)> =
+            report.entries.iter().map(|x| (x.1, x.2.as_str())).collect();
+        assert_eq!(
+            skipped,
+            vec![
+                (4, "malformed line"),
+                (2, "unknown pivot"),
+                (3, "unknown sec")
+            ]
+        );
+
+        let apath = Path::new("test/anchors_unknown.tsv");
+        let err = links_obj
+            .read_anchors(apath.to_path_buf(), &mut SkipReport::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("test/anchors_unknown.tsv:2"));
+
+        links_obj
+            .read_anchors(apath.to_path_buf(), &mut report)
+            .unwrap();
+        assert_eq!(report.len(), 5);
+        assert_eq!(links_obj.jump_cell_id(0, 0.9), 1);
+    }
+
+    #[test]
+    fn test_constructors() {
+        let mm_obj = fixtures::experiment();
+
+        let opath = Path::new("test/olaps.tsv");
+        let mpath = Path::new("test/microclusters.tsv");
+        let apath = Path::new("test/anchors.tsv");
+        for (with_microclusters, with_anchors) in
+            [(false, false), (true, false), (false, true), (true, true)]
+        {
+            let mut report = SkipReport::default();
+            let mut links_obj = Links::new(
+                &mm_obj,
+                opath.to_path_buf(),
+                0,
+                1,
+                Orientation::Auto,
+                &mut report,
+            )
+            .unwrap();
+            if with_microclusters {
+                links_obj
+                    .read_microclusters(mpath.to_path_buf(), &mut report)
+                    .unwrap();
+            }
+            if with_anchors {
+                links_obj
+                    .read_anchors(apath.to_path_buf(), &mut report)
+                    .unwrap();
             }
-            ._sort()
+
+            assert_eq!(links_obj.len(), 4);
+            assert_eq!(links_obj.has_microclusters(), with_microclusters);
+            assert_eq!(links_obj.has_anchors(), with_anchors);
+
+            // the rows of a microcluster are local, the anchors are keyed by
+            // the global cells
+            let cells = match with_microclusters {
+                true => links_obj.microcluster().unwrap()["b"].clone(),
+                false => (0..mm_obj.num_cells(0)).collect(),
+            };
+            let cell_index = mm_obj.cell_index(0, Some(&cells));
+            let jumps: Vec<Vec<(usize, f32)>> = (0..cell_index.len())
+                .map(|row| links_obj.jump_probabilities(cell_index.to_global(row)))
+                .collect();
+
+            let expected = match (with_microclusters, with_anchors) {
+                (false, false) => vec![
+                    vec![(0, 1.0)],
+                    vec![(1, 1.0)],
+                    vec![(2, 1.0)],
+                    vec![(3, 1.0)],
+                    vec![(4, 1.0)],
+                ],
+                (true, false) => vec![vec![(1, 1.0)], vec![(3, 1.0)], vec![(4, 1.0)]],
+                (false, true) => vec![
+                    vec![(1, 0.25), (3, 0.75)],
+                    vec![(1, 1.0)],
+                    vec![(2, 1.0)],
+                    vec![(0, 1.0)],
+                    vec![(2, 1.0)],
+                ],
+                (true, true) => vec![vec![(1, 1.0)], vec![(0, 1.0)], vec![(2, 1.0)]],
+            };
+            assert_eq!(jumps, expected);
+        }
+    }
+
+    #[test]
+    fn test_reconciled_anchors() {
+        let spath = Path::new("test/sec");
+        let rpath = Path::new("test/pivot_reordered");
+        let mut mm_obj =
+            MultiModalExperiment::from_paths(vec![spath.to_path_buf(), rpath.to_path_buf()]);
+        mm_obj
+            .reconcile_barcodes(BarcodePolicy::Intersect, true)
+            .unwrap();
+
+        // the anchors name the pivot cells by the raw barcodes of the pivot,
+        // not by the reconciled ones taken from the sec modality
+        let mut report = SkipReport::new(Policy::Lenient);
+        let mut links_obj = Links::new(
+            &mm_obj,
+            Path::new("test/olaps.tsv").to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut report,
+        )
+        .unwrap();
+        links_obj
+            .read_anchors(
+                Path::new("test/anchors_reordered.tsv").to_path_buf(),
+                &mut report,
+            )
+            .unwrap();
+
+        let jumps: Vec<usize> = (0..mm_obj.num_cells(0))
+            .map(|cell| links_obj.jump_cell_id(cell, 0.5))
+            .collect();
+        assert_eq!(jumps, vec![3, 0, 1, 3]);
+        let skipped: Vec<&str> = report.entries.iter().map(|x| x.3.as_str()).collect();
+        assert_eq!(
+            skipped,
+            vec!["AAACAGCCAATCCCTT-1", "rna#AAACAGCCAATCCCTT_1"]
+        );
+    }
+
+    #[test]
+    fn test_limit_region_size() {
+        let mm_obj = fixtures::experiment();
+
+        let mut report = SkipReport::default();
+        let opath = Path::new("test/olaps.tsv");
+        let mut links_obj = Links::new(
+            &mm_obj,
+            opath.to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(links_obj.limit_region_size(6), 0);
+
+        // without weights the links of the most connected features go first,
+        // leaving the peaks 4 & 5 of AP006222.2 without any link
+        assert_eq!(links_obj.limit_region_size(4), 3);
+        let groups = links_obj.extract_iqr().unwrap();
+        assert_eq!(groups.groups(), &vec![vec![0, 1], vec![2], vec![3]]);
+        assert_eq!(links_obj.entry_to_pivot(7), &vec![1]);
+        assert_eq!(links_obj.get_from_pivot_hits(&[2]), vec![1, 2, 3]);
+        assert_eq!(links_obj.get_sec_features().len(), 6);
+
+        let mut secs = Vec::new();
+        for group in groups.groups() {
+            secs.extend(links_obj.get_from_pivot_hits(group));
+        }
+        let num_secs = secs.len();
+        secs.sort();
+        secs.dedup();
+        assert_eq!(secs.len(), num_secs);
+
+        // the weakest link joining the region goes, the zero weighted one stays
+        // as it only adds a single feature
+        let wpath = Path::new("test/olaps_weighted.tsv");
+        let mut links_obj = Links::new(
+            &mm_obj,
+            wpath.to_path_buf(),
+            0,
+            1,
+            Orientation::Auto,
+            &mut report,
+        )
+        .unwrap();
+        links_obj.limit_region_size(4);
+        assert_eq!(links_obj.entry_to_pivot(7), &vec![3]);
+        assert_eq!(links_obj.entry_to_pivot(0), &vec![0, 1]);
+        assert!(!links_obj.weights.as_ref().unwrap().contains_key(&(7, 1)));
+        assert_eq!(
+            links_obj.extract_iqr().unwrap().groups(),
+            &vec![vec![0, 1], vec![2], vec![3]]
         );
     }
 }
@@ -1,6 +1,7 @@
 extern crate clap;
 extern crate crossbeam;
 extern crate csv;
+extern crate flate2;
 extern crate indicatif;
 extern crate pretty_env_logger;
 
@@ -14,18 +15,39 @@ extern crate sce;
 use clap::{App, Arg, SubCommand};
 use std::error::Error;
 
+mod alias;
+mod bootstrap;
 mod configs;
+mod exact;
+#[cfg(test)]
+mod fixtures;
+mod genomic;
 mod gibbs;
 mod links;
+mod mtx;
 mod multimodal;
+mod permutation;
+mod regions;
 mod spatial;
+mod summary;
 mod unify;
+mod walk;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let num_threads = configs::NUM_THREADS.to_string();
     let matches = App::new("indus")
         .version("0.1.0")
         .author("Avi Srivastava")
         .about("Generate summary stats for multimodal data.")
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .short("t")
+                .takes_value(true)
+                .global(true)
+                .default_value(&num_threads)
+                .help("number of worker threads."),
+        )
         .subcommand(
             SubCommand::with_name("autocorr")
                 .about("A subcommand to generate auto-correlation summary statistics.")
@@ -74,13 +96,56 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .multiple(true)
                         .help("path to the parent folders of matrices."),
                 )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("names of the modalities, in the order of the matrices."),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .takes_value(true)
+                        .default_value("error")
+                        .possible_values(&["error", "intersect", "union", "unpaired"])
+                        .help("reconcile differing barcodes of the modalities by failing, as by default, keeping the shared cells, keeping all cells or keeping them unpaired, where modalities on differing barcodes need anchors."),
+                )
+                .arg(
+                    Arg::with_name("loosebarcodes")
+                        .long("loose-barcodes")
+                        .help("also match barcodes with sample prefixes or _1 suffixes, e.g. atac#AAACAGCCAAGGAATC_1, by their nucleotides, which can merge distinct barcodes."),
+                )
                 .arg(
                     Arg::with_name("links")
                         .long("links")
                         .short("l")
                         .takes_value(true)
                         .required(true)
-                        .help("path to the file with feature links."),
+                        .multiple(true)
+                        .help("path to the files with feature links, one per link of the chain, with an optional third column of link weights."),
+                )
+                .arg(
+                    Arg::with_name("orientation")
+                        .long("orientation")
+                        .takes_value(true)
+                        .default_value("auto")
+                        .possible_values(&["auto", "forward", "reversed"])
+                        .help("order of the features in the links files, sec then pivot for forward, auto fails unless one order matches clearly more records."),
+                )
+                .arg(
+                    Arg::with_name("pivot")
+                        .long("pivot")
+                        .takes_value(true)
+                        .conflicts_with("chain")
+                        .help("pivot modality, by name or index (default the last one)."),
+                )
+                .arg(
+                    Arg::with_name("chain")
+                        .long("chain")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("modalities to walk, by name or index (default all, ending at the pivot). Chains of more than two modalities, given or by default, are sampled as whole paths of links over paired cells, capped by --max-paths, and reject --anchors, --microclusters, --exact-max-states, --bootstrap, --permutations, --mtx, --summary and --cell-attribution."),
                 )
                 .arg(
                     Arg::with_name("anchors")
@@ -94,7 +159,128 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .long("microclusters")
                         .short("m")
                         .takes_value(true)
-                        .help("path to the file with microclusters of pivot assay."),
+                        .help("path to the file with microclusters of the cells of the sec assay, by their barcodes."),
+                )
+                .arg(
+                    Arg::with_name("minsamples")
+                        .long("min-samples")
+                        .takes_value(true)
+                        .help("minimum number of samples per region (default 10000)."),
+                )
+                .arg(
+                    Arg::with_name("maxsamples")
+                        .long("max-samples")
+                        .takes_value(true)
+                        .help("maximum number of samples per region (default 1000000)."),
+                )
+                .arg(
+                    Arg::with_name("samplesperstate")
+                        .long("samples-per-state")
+                        .takes_value(true)
+                        .help("samples per sec-pivot state of a region (default 10000)."),
+                )
+                .arg(
+                    Arg::with_name("mcse")
+                        .long("mcse")
+                        .takes_value(true)
+                        .help("sample each region until this Monte-Carlo standard error."),
+                )
+                .arg(
+                    Arg::with_name("exact")
+                        .long("exact-max-states")
+                        .takes_value(true)
+                        .help("solve regions up to this many states exactly instead of sampling them, 0 sampling every region (default 64)."),
+                )
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .takes_value(true)
+                        .help("number of cell bootstrap replicates per region (default 0)."),
+                )
+                .arg(
+                    Arg::with_name("cilevel")
+                        .long("ci-level")
+                        .takes_value(true)
+                        .help("coverage of the bootstrap percentile interval (default 0.95)."),
+                )
+                .arg(
+                    Arg::with_name("permutations")
+                        .long("permutations")
+                        .takes_value(true)
+                        .help("number of pivot cell permutations for the null (default 0)."),
+                )
+                .arg(
+                    Arg::with_name("permute")
+                        .long("permute")
+                        .takes_value(true)
+                        .possible_values(&["cells", "microclusters"])
+                        .help("permute all the pivot cells or only within microclusters."),
+                )
+                .arg(
+                    Arg::with_name("chains")
+                        .long("chains")
+                        .takes_value(true)
+                        .help("number of independent chains per region (default 4)."),
+                )
+                .arg(
+                    Arg::with_name("rhat")
+                        .long("rhat")
+                        .takes_value(true)
+                        .help("R-hat threshold for flagging a region (default 1.1)."),
+                )
+                .arg(
+                    Arg::with_name("burnin")
+                        .long("burnin")
+                        .takes_value(true)
+                        .help("number of initial iterations discarded per chain (default 10000)."),
+                )
+                .arg(
+                    Arg::with_name("thin")
+                        .long("thin")
+                        .takes_value(true)
+                        .help("keep every n-th iteration after burn-in (default 1)."),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .help("seed for reproducible sampling (default random)."),
+                )
+                .arg(
+                    Arg::with_name("lenient")
+                        .long("lenient")
+                        .takes_value(true)
+                        .help("skip entries with unknown features or cells, or malformed ones, instead of failing and report them to a file."),
+                )
+                .arg(
+                    Arg::with_name("maxregion")
+                        .long("max-region-size")
+                        .takes_value(true)
+                        .help("split regions with more sec and pivot features by dropping their weakest links."),
+                )
+                .arg(
+                    Arg::with_name("maxpaths")
+                        .long("max-paths")
+                        .takes_value(true)
+                        .help("fail on regions of a chain of more than two modalities with more complete paths (default 100000)."),
+                )
+                .arg(
+                    Arg::with_name("mtx")
+                        .long("mtx")
+                        .takes_value(true)
+                        .help("also write gamma as a 10x style MatrixMarket directory."),
+                )
+                .arg(
+                    Arg::with_name("attribution")
+                        .long("cell-attribution")
+                        .takes_value(true)
+                        .help("also write a cells by links sample count matrix to a directory."),
+                )
+                .arg(
+                    Arg::with_name("summary")
+                        .long("summary")
+                        .takes_value(true)
+                        .help("also write per sec and per pivot feature summaries to a directory."),
                 )
                 .arg(
                     Arg::with_name("output")
@@ -105,15 +291,183 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("path to the output path file."),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("links")
+                .about("A subcommand to link peaks to genes by their genomic coordinates.")
+                .arg(
+                    Arg::with_name("features")
+                        .long("features")
+                        .short("f")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the peak names, e.g. the genes.tsv of the sec matrix."),
+                )
+                .arg(
+                    Arg::with_name("annotation")
+                        .long("annotation")
+                        .short("a")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the gene annotation, a GTF or GFF or a (TSS) BED file, possibly gzipped."),
+                )
+                .arg(
+                    Arg::with_name("genes")
+                        .long("genes")
+                        .short("g")
+                        .takes_value(true)
+                        .help("only link the genes in this file, e.g. the genes.tsv of the pivot matrix, matched by id or name, the links naming them as matched."),
+                )
+                .arg(
+                    Arg::with_name("mode")
+                        .long("mode")
+                        .takes_value(true)
+                        .default_value("tss")
+                        .possible_values(&["tss", "body"])
+                        .help("link peaks around the TSS or around the gene body."),
+                )
+                .arg(
+                    Arg::with_name("window")
+                        .long("window")
+                        .short("w")
+                        .takes_value(true)
+                        .default_value("50000")
+                        .help("bases on either side of the TSS or the gene body."),
+                )
+                .arg(
+                    Arg::with_name("decay")
+                        .long("decay")
+                        .takes_value(true)
+                        .help("add a link weight exp(-distance / decay) as the third column."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output links file."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("regions")
+                .about("A subcommand to export the independently quantifiable regions of the links.")
+                .arg(
+                    Arg::with_name("ipaths")
+                        .long("ipaths")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .multiple(true)
+                        .help("path to the parent folders of matrices."),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("names of the modalities, in the order of the matrices."),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .takes_value(true)
+                        .default_value("error")
+                        .possible_values(&["error", "intersect", "union", "unpaired"])
+                        .help("reconcile differing barcodes of the modalities, regions don't depend on the cells."),
+                )
+                .arg(
+                    Arg::with_name("loosebarcodes")
+                        .long("loose-barcodes")
+                        .help("also match barcodes with sample prefixes or _1 suffixes, e.g. atac#AAACAGCCAAGGAATC_1, by their nucleotides, which can merge distinct barcodes."),
+                )
+                .arg(
+                    Arg::with_name("links")
+                        .long("links")
+                        .short("l")
+                        .takes_value(true)
+                        .required(true)
+                        .multiple(true)
+                        .help("path to the files with feature links, one per link of the chain, with an optional third column of link weights."),
+                )
+                .arg(
+                    Arg::with_name("orientation")
+                        .long("orientation")
+                        .takes_value(true)
+                        .default_value("auto")
+                        .possible_values(&["auto", "forward", "reversed"])
+                        .help("order of the features in the links files, sec then pivot for forward, auto fails unless one order matches clearly more records."),
+                )
+                .arg(
+                    Arg::with_name("pivot")
+                        .long("pivot")
+                        .takes_value(true)
+                        .conflicts_with("chain")
+                        .help("pivot modality, by name or index (default the last one)."),
+                )
+                .arg(
+                    Arg::with_name("chain")
+                        .long("chain")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("modalities of the chain, by name or index (default all, ending at the pivot), the regions of every link being written on their own."),
+                )
+                .arg(
+                    Arg::with_name("lenient")
+                        .long("lenient")
+                        .takes_value(true)
+                        .help("skip entries with unknown features, or malformed ones, instead of failing and report them to a file."),
+                )
+                .arg(
+                    Arg::with_name("maxregion")
+                        .long("max-region-size")
+                        .takes_value(true)
+                        .help("split regions with more sec and pivot features by dropping their weakest links."),
+                )
+                .arg(
+                    Arg::with_name("histogram")
+                        .long("histogram")
+                        .takes_value(true)
+                        .help("also write the number of regions per size to this file, the histogram is logged either way."),
+                )
+                .arg(
+                    Arg::with_name("dot")
+                        .long("dot")
+                        .takes_value(true)
+                        .help("also write the links of a region as a Graphviz DOT file."),
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .long("region")
+                        .takes_value(true)
+                        .requires("dot")
+                        .help("region to write as DOT, by id or by any of its features (default the largest)."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output regions file."),
+                ),
+        )
         .get_matches();
     pretty_env_logger::init_timed();
 
     if let Some(sub_m) = matches.subcommand_matches("gamma") {
-        unify::callback(&sub_m)?
+        unify::callback(sub_m)?
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("regions") {
+        regions::callback(sub_m)?
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("links") {
+        genomic::callback(sub_m)?
     }
 
     if let Some(sub_m) = matches.subcommand_matches("autocorr") {
-        spatial::callback(&sub_m)?
+        spatial::callback(sub_m)?
     }
 
     Ok(())
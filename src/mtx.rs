@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::gibbs;
+use crate::links;
+use crate::multimodal;
+
+// Global sec by pivot gamma matrix, gathered region by region. Regions don't
+// share any features so every link is added at most once.
+pub struct GammaMatrix {
+    triplets: Vec<(usize, usize, f32)>,
+}
+
+impl GammaMatrix {
+    pub fn new() -> GammaMatrix {
+        GammaMatrix {
+            triplets: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, gamma: &gibbs::Gamma, sec_feats: &[usize], pivot_feats: &[usize]) {
+        let num_pivots = pivot_feats.len();
+        for (mat_index, &val) in gamma.values().iter().enumerate() {
+            if val == 0.0 {
+                continue;
+            }
+
+            let sec = sec_feats[mat_index / num_pivots];
+            let pivot = pivot_feats[mat_index % num_pivots];
+            self.triplets.push((sec, pivot, val));
+        }
+    }
+
+    // sec features along the rows and pivot features along the columns
+    pub fn write(
+        &mut self,
+        dir: &Path,
+        mm_obj: &multimodal::MultiModalExperiment<f32>,
+        links_obj: &links::Links<f32>,
+    ) -> Result<(), Box<dyn Error>> {
+        let features = mm_obj.features();
+        write_tenx(
+            dir,
+            features[links_obj.sec_assay()],
+            features[links_obj.pivot_assay()],
+            "real",
+            &mut self.triplets,
+        )
+    }
+}
+
+// Cells by links matrix counting, for every cell, the recorded samples of the
+// chain it drove into each link. Links are named as sec|pivot.
+pub struct AttributionMatrix {
+    links: Vec<String>,
+    triplets: Vec<(usize, usize, u32)>,
+}
+
+impl AttributionMatrix {
+    pub fn new() -> AttributionMatrix {
+        AttributionMatrix {
+            links: Vec::new(),
+            triplets: Vec::new(),
+        }
+    }
+
+    // exactly solved regions have no chain, hence no cells to attribute
+    pub fn add(
+        &mut self,
+        gamma: &gibbs::Gamma,
+        sec_feats: &[usize],
+        pivot_feats: &[usize],
+        links_obj: &links::Links<f32>,
+    ) {
+        let cell_stats = match gamma.cell_stats() {
+            Some(cell_stats) => cell_stats,
+            None => return,
+        };
+
+        let num_pivots = pivot_feats.len();
+        let mut columns = HashMap::new();
+        for (mat_index, &val) in gamma.values().iter().enumerate() {
+            if val == 0.0 {
+                continue;
+            }
+
+            columns.insert(mat_index, self.links.len());
+            self.links.push(format!(
+                "{}|{}",
+                links_obj.feature_string(false, sec_feats[mat_index / num_pivots]),
+                links_obj.feature_string(true, pivot_feats[mat_index % num_pivots]),
+            ));
+        }
+
+        for (&(cell_id, mat_index), &count) in cell_stats {
+            self.triplets.push((cell_id, columns[&mat_index], count));
+        }
+    }
+
+    // rows are the cells of the sec modality, the ones driving the chains
+    pub fn write(
+        &mut self,
+        dir: &Path,
+        mm_obj: &multimodal::MultiModalExperiment<f32>,
+        links_obj: &links::Links<f32>,
+    ) -> Result<(), Box<dyn Error>> {
+        write_tenx(
+            dir,
+            mm_obj.cells(links_obj.sec_assay()),
+            &self.links,
+            "integer",
+            &mut self.triplets,
+        )
+    }
+}
+
+// 10x layout, i.e. barcodes.tsv names the rows, genes.tsv the columns and
+// matrix.mtx holds the 1-based triplets
+fn write_tenx<T: Display>(
+    dir: &Path,
+    row_names: &[String],
+    col_names: &[String],
+    field: &str,
+    triplets: &mut [(usize, usize, T)],
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut rows_file = BufWriter::new(File::create(dir.join("barcodes.tsv"))?);
+    for name in row_names {
+        writeln!(rows_file, "{}", name)?;
+    }
+
+    let mut cols_file = BufWriter::new(File::create(dir.join("genes.tsv"))?);
+    for name in col_names {
+        writeln!(cols_file, "{}", name)?;
+    }
+
+    triplets.sort_by_key(|a| (a.0, a.1));
+
+    let mut ofile = BufWriter::new(File::create(dir.join("matrix.mtx"))?);
+    writeln!(ofile, "%%MatrixMarket matrix coordinate {} general", field)?;
+    writeln!(
+        ofile,
+        "{} {} {}",
+        row_names.len(),
+        col_names.len(),
+        triplets.len()
+    )?;
+    for (row, col, val) in triplets.iter() {
+        writeln!(ofile, "{} {} {}", row + 1, col + 1, val)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configs::GibbsConfig;
+    use crate::fixtures;
+    use crate::gibbs;
+    use crate::mtx;
+
+    #[test]
+    fn test_mtx() {
+        let mm_obj = fixtures::experiment();
+        let links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+
+        let config = GibbsConfig {
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+
+        let mut matrix = mtx::GammaMatrix::new();
+        let mut expected = Vec::new();
+        for pivot_feats in links_obj.extract_iqr().unwrap().groups() {
+            let sec_feats = links_obj.get_from_pivot_hits(pivot_feats);
+            let gamma = gibbs::estimate_region(
+                &sec_feats,
+                pivot_feats,
+                &config,
+                &links_obj,
+                &mm_obj,
+                None,
+                None,
+            )
+            .unwrap();
+
+            matrix.add(&gamma, &sec_feats, pivot_feats);
+            expected.push((gamma, sec_feats, pivot_feats.clone()));
+        }
+
+        let dir = std::env::temp_dir().join("indus_test_mtx");
+        matrix.write(&dir, &mm_obj, &links_obj).unwrap();
+        let sce_obj = sce::SingleCellExperiment::<f32>::from_tenx_v2(dir.clone()).unwrap();
+
+        assert_eq!(sce_obj.row_names(), mm_obj.features()[0]);
+        assert_eq!(sce_obj.col_names(), mm_obj.features()[1]);
+        for (gamma, sec_feats, pivot_feats) in expected {
+            for (mat_index, &val) in gamma.values().iter().enumerate() {
+                let sec = sec_feats[mat_index / pivot_feats.len()];
+                let pivot = pivot_feats[mat_index % pivot_feats.len()];
+                let stored = sce_obj.counts().get(sec, pivot).cloned().unwrap_or(0.0);
+                assert!((stored - val).abs() < 1e-6);
+            }
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
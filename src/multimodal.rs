@@ -1,19 +1,233 @@
-use rand::Rng;
 use sce::SingleCellExperiment;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 use std::path::PathBuf;
 
 pub struct MultiModalExperiment<T> {
-    assays: Vec<SingleCellExperiment<T>>,
-    _pivot: usize,
+    columns: Vec<ColumnMajor<T>>,
+    features: Vec<Vec<String>>,
+    barcodes: Vec<Vec<String>>,
+    names: Vec<String>,
+    pivot: usize,
+    is_loose: bool,
+}
+
+// How the barcodes of the modalities are reconciled on load. Unpaired keeps
+// every modality in its own barcode space, the others bring all of them to
+// the same cells in the same order, failing on any difference, keeping only
+// the shared cells or keeping all of them with zero filled rows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarcodePolicy {
+    Unpaired,
+    Error,
+    Intersect,
+    Union,
+}
+
+// AAACAGCCAAGGAATC-1 and AAACAGCCAAGGAATC are the same cell, a missing 10x gem
+// group suffix standing for the first one, anything else has to match as is
+pub fn normalize_barcode(barcode: &str) -> String {
+    let mut toks = barcode.rsplitn(2, '-');
+    let suffix = toks.next().unwrap();
+    let has_suffix =
+        toks.next().is_some() && !suffix.is_empty() && suffix.chars().all(|x| x.is_ascii_digit());
+    match has_suffix {
+        true => barcode.to_string(),
+        false => format!("{}-1", barcode),
+    }
+}
+
+// Also matches AAACAGCCAAGGAATC_1 and prefixed barcodes like
+// atac#AAACAGCCAAGGAATC-1 or atac_AAACAGCCAAGGAATC-1, found as the longest run
+// of nucleotides along with the following number. Being only opted into, as
+// it can merge distinct barcodes, e.g. of two samples sharing a nucleotide run.
+pub fn loose_barcode(barcode: &str) -> String {
+    let bytes = barcode.as_bytes();
+    let is_base = |x: &u8| b"ACGTN".contains(x);
+
+    let (mut start, mut len) = (0, 0);
+    let mut index = 0;
+    while index < bytes.len() {
+        let run = bytes[index..].iter().take_while(|&x| is_base(x)).count();
+        if run > len {
+            start = index;
+            len = run;
+        }
+        index += run.max(1);
+    }
+    if len == 0 {
+        return barcode.to_string();
+    }
+
+    let rest = &barcode[start + len..];
+    let suffix: String = rest
+        .trim_start_matches(['-', '_', '.'])
+        .chars()
+        .take_while(|x| x.is_ascii_digit())
+        .collect();
+    match suffix.is_empty() {
+        true => format!("{}-1", &barcode[start..start + len]),
+        false => format!("{}-{}", &barcode[start..start + len], suffix),
+    }
+}
+
+// Feature major copy of an assay's counts, to gather a few features cheaply.
+// It is the only copy kept, the cell major counts are dropped once loaded.
+struct ColumnMajor<T> {
+    indptr: Vec<usize>,
+    cells: Vec<usize>,
+    data: Vec<T>,
+}
+
+impl<T: Copy + Default> ColumnMajor<T> {
+    // counting sort of the nonzeros by feature, the cells of every feature
+    // stay in the increasing order the counts are iterated in
+    fn new(experiment: &SingleCellExperiment<T>) -> ColumnMajor<T> {
+        let counts = experiment.counts();
+        let num_features = experiment.cols();
+
+        let mut indptr = vec![0; num_features + 1];
+        for (_, (_, feature)) in counts.iter() {
+            indptr[feature + 1] += 1;
+        }
+        for index in 0..num_features {
+            indptr[index + 1] += indptr[index];
+        }
+
+        let mut next = indptr.clone();
+        let mut cells = vec![0; indptr[num_features]];
+        let mut data = vec![T::default(); indptr[num_features]];
+        for (&val, (cell, feature)) in counts.iter() {
+            cells[next[feature]] = cell;
+            data[next[feature]] = val;
+            next[feature] += 1;
+        }
+
+        ColumnMajor {
+            indptr,
+            cells,
+            data,
+        }
+    }
+
+    fn column(&self, feature: usize) -> (&[usize], &[T]) {
+        let (start, end) = (self.indptr[feature], self.indptr[feature + 1]);
+        (&self.cells[start..end], &self.data[start..end])
+    }
+
+    // moves every cell to its reconciled row, dropping the ones without any,
+    // compacting the columns in place
+    fn remap(&mut self, rows: &[Option<usize>]) {
+        let mut entries: Vec<(usize, T)> = Vec::new();
+        let (mut start, mut old_start) = (0, 0);
+        for feature in 0..self.indptr.len() - 1 {
+            let old_end = self.indptr[feature + 1];
+            entries.clear();
+            for index in old_start..old_end {
+                if let Some(row) = rows[self.cells[index]] {
+                    entries.push((row, self.data[index]));
+                }
+            }
+            entries.sort_by_key(|x| x.0);
+
+            for (offset, &(row, value)) in entries.iter().enumerate() {
+                self.cells[start + offset] = row;
+                self.data[start + offset] = value;
+            }
+            start += entries.len();
+            old_start = old_end;
+            self.indptr[feature + 1] = start;
+        }
+
+        self.cells.truncate(start);
+        self.data.truncate(start);
+    }
+}
+
+// Local rows of a submatrix restricted to a subset of the cells, e.g. the cells
+// of a microcluster or a bootstrap resample, against the global cell indices.
+// Anchors, permutations and the full pivot matrix all use the global indices,
+// so a local row has to go through to_global before reaching any of them.
+#[derive(Debug)]
+pub struct CellIndex<'a> {
+    num_cells: usize,
+    cells: Option<&'a [usize]>,
+    rows: HashMap<usize, Vec<usize>>,
+}
+
+impl<'a> CellIndex<'a> {
+    // repeated cells get a local row each
+    pub fn new(num_cells: usize, cells: Option<&'a [usize]>) -> CellIndex<'a> {
+        let mut rows = HashMap::<usize, Vec<usize>>::new();
+        if let Some(cells) = cells {
+            for (row, &cell) in cells.iter().enumerate() {
+                assert!(cell < num_cells, "cell index out of bounds");
+                rows.entry(cell).or_default().push(row);
+            }
+        }
+
+        CellIndex {
+            num_cells,
+            cells,
+            rows,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.map_or(self.num_cells, |cells| cells.len())
+    }
+
+    pub fn to_global(&self, row: usize) -> usize {
+        self.cells.map_or(row, |cells| cells[row])
+    }
+}
+
+// Cell major sparse matrix restricted to the features of a region, holding
+// only the cells with at least one nonzero value among those features.
+#[derive(Debug, PartialEq)]
+pub struct SubMatrix {
+    num_rows: usize,
+    rows: Vec<usize>,
+    indptr: Vec<usize>,
+    cols: Vec<usize>,
+    data: Vec<f32>,
+}
+
+impl SubMatrix {
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> f32 {
+        match self.rows.binary_search(&row) {
+            Ok(index) => {
+                let (start, end) = (self.indptr[index], self.indptr[index + 1]);
+                match self.cols[start..end].binary_search(&col) {
+                    Ok(offset) => self.data[start + offset],
+                    Err(_) => 0.0,
+                }
+            }
+            Err(_) => 0.0,
+        }
+    }
 }
 
 impl<T> fmt::Debug for MultiModalExperiment<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "MultiModalExperiment: {} modalitles\n", self.len())?;
-        for (index, exp) in self.assays().into_iter().enumerate() {
-            write!(f, "Modality {} Shape: {:?}\n", index, exp.shape())?;
+        writeln!(f, "MultiModalExperiment: {} modalitles", self.len())?;
+        for (index, features) in self.features.iter().enumerate() {
+            writeln!(
+                f,
+                "Modality {} ({}) Shape: {:?}",
+                index,
+                self.names[index],
+                (self.num_cells(index), features.len())
+            )?;
         }
 
         Ok(())
@@ -21,159 +235,461 @@ impl<T> fmt::Debug for MultiModalExperiment<T> {
 }
 
 impl<T> MultiModalExperiment<T> {
-    pub fn get_experiment(&self, index: usize) -> Option<&SingleCellExperiment<T>> {
-        self.assays.get(index)
+    pub fn len(&self) -> usize {
+        self.names.len()
     }
 
-    pub fn len(&self) -> usize {
-        self.assays.len()
+    pub fn names(&self) -> &Vec<String> {
+        &self.names
     }
 
-    pub fn assays(&self) -> &Vec<SingleCellExperiment<T>> {
-        &self.assays
+    // position of a modality given either its name or its index
+    pub fn assay_index(&self, name: &str) -> Option<usize> {
+        match self.names.iter().position(|x| x == name) {
+            Some(index) => Some(index),
+            None => name.parse::<usize>().ok().filter(|&x| x < self.len()),
+        }
     }
 
-    pub fn _pivot(&self) -> usize {
-        self._pivot
+    pub fn pivot(&self) -> usize {
+        self.pivot
     }
 
-    pub fn num_cells(&self) -> usize {
-        self.get_experiment(0).unwrap().rows()
+    pub fn set_pivot(&mut self, pivot: usize) {
+        assert!(pivot < self.len(), "pivot has to be one of the modalities");
+        self.pivot = pivot;
+    }
+
+    // default chain from every other modality, in the given order, to the pivot
+    pub fn chain(&self) -> Vec<usize> {
+        let mut chain: Vec<usize> = (0..self.len()).filter(|&x| x != self.pivot).collect();
+        chain.push(self.pivot);
+        chain
+    }
+
+    // every modality has its own barcodes, unpaired ones only meet through
+    // the anchors
+    pub fn num_cells(&self, assay: usize) -> usize {
+        self.barcodes[assay].len()
     }
 
     pub fn features(&self) -> Vec<&Vec<String>> {
-        let mut features = Vec::new();
-        for assay in &self.assays {
-            features.push(assay.col_names());
+        self.features.iter().collect()
+    }
+
+    pub fn cells(&self, assay: usize) -> &Vec<String> {
+        &self.barcodes[assay]
+    }
+
+    // key the barcodes of the modalities were reconciled on, for matching the
+    // barcodes of the other inputs to the cells
+    pub fn barcode_key(&self, barcode: &str) -> String {
+        match self.is_loose {
+            true => loose_barcode(barcode),
+            false => normalize_barcode(barcode),
         }
+    }
 
-        features
+    // cells of a modality by the key of their barcodes
+    pub fn cell_keys(&self, assay: usize) -> HashMap<String, usize> {
+        self.barcodes[assay]
+            .iter()
+            .enumerate()
+            .map(|(index, barcode)| (self.barcode_key(barcode), index))
+            .collect()
     }
 
-    pub fn cells(&self) -> &Vec<String> {
-        self.get_experiment(0).unwrap().row_names()
+    // modalities measured on the very same cells
+    pub fn is_paired(&self, first: usize, second: usize) -> bool {
+        self.cells(first) == self.cells(second)
     }
 
-    pub fn get_feature_string(&self, is_pivot: bool, index: usize) -> &str {
-        let assay = match is_pivot {
-            true => &self.assays()[1],
-            false => &self.assays()[0],
-        };
-        &assay.col_names()[index]
+    pub fn get_feature_string(&self, assay: usize, index: usize) -> &str {
+        &self.features[assay][index]
     }
 }
 
 impl MultiModalExperiment<f32> {
+    // modalities are named by their position
     pub fn from_paths(paths: Vec<PathBuf>) -> MultiModalExperiment<f32> {
-        let mut assays: Vec<sce::SingleCellExperiment<f32>> = Vec::new();
+        let names = (0..paths.len()).map(|x| x.to_string()).collect();
+        MultiModalExperiment::from_named_paths(paths, names)
+    }
+
+    pub fn from_named_paths(paths: Vec<PathBuf>, names: Vec<String>) -> MultiModalExperiment<f32> {
+        assert_eq!(paths.len(), names.len(), "every modality needs a name");
+        let mut columns = Vec::new();
+        let mut features = Vec::new();
+        let mut barcodes = Vec::new();
         for path in paths {
             let experiment = sce::SingleCellExperiment::from_tenx_v2(path)
                 .expect("error reading the input matrix");
 
             info!("{:?}", experiment);
-            assays.push(experiment);
+            columns.push(ColumnMajor::new(&experiment));
+            features.push(experiment.col_names().clone());
+            barcodes.push(experiment.row_names().clone());
         }
 
+        let num_assays = columns.len();
         MultiModalExperiment {
-            assays: assays,
-            _pivot: 0,
+            columns,
+            features,
+            barcodes,
+            names,
+            pivot: num_assays - 1,
+            is_loose: false,
         }
     }
 
-    pub fn get_dense_submatrix(
+    // brings all the modalities to the barcodes of the first one, in its order,
+    // with the cells matched on their normalized, or loose, barcodes
+    pub fn reconcile_barcodes(
+        &mut self,
+        policy: BarcodePolicy,
+        is_loose: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.is_loose = is_loose;
+        if policy == BarcodePolicy::Unpaired {
+            return Ok(());
+        }
+
+        let mut keys = Vec::<Vec<String>>::new();
+        for (assay, barcodes) in self.barcodes.iter().enumerate() {
+            let assay_keys: Vec<String> = barcodes.iter().map(|x| self.barcode_key(x)).collect();
+            let mut seen = HashSet::new();
+            for (key, barcode) in assay_keys.iter().zip(barcodes.iter()) {
+                if !seen.insert(key) {
+                    return Err(format!(
+                        "barcode {} of modality {} repeats after normalization",
+                        barcode, self.names[assay]
+                    )
+                    .into());
+                }
+            }
+            keys.push(assay_keys);
+        }
+
+        let key_sets: Vec<HashSet<&String>> = keys.iter().map(|x| x.iter().collect()).collect();
+        let mut cells: Vec<(&String, &String)> = Vec::new();
+        match policy {
+            BarcodePolicy::Error | BarcodePolicy::Intersect => {
+                for (key, barcode) in keys[0].iter().zip(self.barcodes[0].iter()) {
+                    if key_sets.iter().all(|x| x.contains(key)) {
+                        cells.push((key, barcode));
+                    }
+                }
+            }
+            BarcodePolicy::Union => {
+                let mut seen = HashSet::new();
+                for (assay_keys, barcodes) in keys.iter().zip(self.barcodes.iter()) {
+                    for (key, barcode) in assay_keys.iter().zip(barcodes.iter()) {
+                        if seen.insert(key) {
+                            cells.push((key, barcode));
+                        }
+                    }
+                }
+            }
+            BarcodePolicy::Unpaired => unreachable!(),
+        }
+
+        let rows: HashMap<&String, usize> = cells
+            .iter()
+            .enumerate()
+            .map(|(row, (key, _))| (*key, row))
+            .collect();
+        for (assay, assay_keys) in keys.iter().enumerate() {
+            let num_dropped = assay_keys.iter().filter(|x| !rows.contains_key(x)).count();
+            let num_filled = cells.len() + num_dropped - assay_keys.len();
+            if policy == BarcodePolicy::Error && num_dropped > 0 {
+                return Err(format!(
+                    "{} barcodes of modality {} are missing from the others",
+                    num_dropped, self.names[assay]
+                )
+                .into());
+            }
+            if num_dropped > 0 {
+                warn!(
+                    "Dropped {} of {} cells of modality {}",
+                    num_dropped,
+                    assay_keys.len(),
+                    self.names[assay]
+                );
+            }
+            if num_filled > 0 {
+                warn!(
+                    "Zero filled {} cells missing from modality {}",
+                    num_filled, self.names[assay]
+                );
+            }
+
+            let assay_rows: Vec<Option<usize>> =
+                assay_keys.iter().map(|x| rows.get(x).cloned()).collect();
+            self.columns[assay].remap(&assay_rows);
+        }
+
+        let barcodes: Vec<String> = cells.iter().map(|x| x.1.clone()).collect();
+        info!("Reconciled the modalities to {} cells", barcodes.len());
+        self.barcodes = vec![barcodes; self.len()];
+        Ok(())
+    }
+
+    // index over the barcodes of the given modality
+    pub fn cell_index<'a>(&self, assay: usize, cells: Option<&'a [usize]>) -> CellIndex<'a> {
+        CellIndex::new(self.num_cells(assay), cells)
+    }
+
+    // rows of the submatrix are the local rows of the cell index, while
+    // columns follow the order of the features
+    pub fn get_submatrix(
         &self,
-        cells: Option<&Vec<usize>>,
-        features: &Vec<usize>,
-        is_pivot: bool,
-    ) -> Vec<Vec<f32>> {
-        let full_spmat = match is_pivot {
-            true => self.get_experiment(1).unwrap().counts(),
-            false => self.get_experiment(0).unwrap().counts(),
-        };
-
-        let cells = match cells {
-            Some(cells) => cells.clone(),
-            None => {
-                let cells: Vec<usize> = (0..self.num_cells()).collect();
-                cells
+        cell_index: &CellIndex,
+        features: &[usize],
+        assay_index: usize,
+    ) -> SubMatrix {
+        let num_features = self.features[assay_index].len();
+        let columns = &self.columns[assay_index];
+        assert_eq!(
+            cell_index.num_cells,
+            self.num_cells(assay_index),
+            "cell index over the barcodes of another modality"
+        );
+
+        let mut triplets = Vec::new();
+        for (c_idx, &feature) in features.iter().enumerate() {
+            assert!(feature < num_features);
+            let (feature_cells, values) = columns.column(feature);
+            for (&cell, &value) in feature_cells.iter().zip(values.iter()) {
+                match cell_index.cells {
+                    Some(_) => {
+                        for &r_idx in cell_index.rows.get(&cell).into_iter().flatten() {
+                            triplets.push((r_idx, c_idx, value));
+                        }
+                    }
+                    None => triplets.push((cell, c_idx, value)),
+                }
             }
-        };
-
-        let num_feats = full_spmat.cols();
-        let mut mat = vec![vec![0.0_f32; features.len()]; cells.len()];
-        for (r_idx, cell) in cells.iter().enumerate() {
-            for (c_idx, feature) in features.iter().enumerate() {
-                assert!(*cell < self.num_cells() && *feature < num_feats);
-                mat[r_idx][c_idx] = *full_spmat.get(*cell, *feature).unwrap_or(&0.0);
+        }
+        triplets.sort_by_key(|x| (x.0, x.1));
+
+        let mut rows = Vec::new();
+        let mut indptr = vec![0];
+        for (index, &(r_idx, _, _)) in triplets.iter().enumerate() {
+            if rows.last() != Some(&r_idx) {
+                if !rows.is_empty() {
+                    indptr.push(index);
+                }
+                rows.push(r_idx);
             }
         }
+        if !rows.is_empty() {
+            indptr.push(triplets.len());
+        }
 
-        mat
+        SubMatrix {
+            num_rows: cell_index.len(),
+            rows,
+            indptr,
+            cols: triplets.iter().map(|x| x.1).collect(),
+            data: triplets.iter().map(|x| x.2).collect(),
+        }
     }
 
-    pub fn choose_feature(
+    // the distribution the sampler draws a feature from for the given cell,
+    // with the values of the features scaled by their prior weights
+    pub fn feature_probabilities(
         &self,
-        mat: &Vec<Vec<f32>>,
-        features: &Vec<usize>,
-        coin_val: f32,
+        mat: &SubMatrix,
+        features: &[usize],
+        priors: &[f32],
         cell_id: usize,
-    ) -> Result<usize, Box<dyn Error>> {
+    ) -> Vec<f32> {
+        let uniform = vec![1.0 / features.len() as f32; features.len()];
         if features.len() == 1 {
-            return Ok(features[0]);
+            return uniform;
         }
 
-        assert!(coin_val < 1.0 && coin_val >= 0.0, "wrong coin toss value");
-        let mut stats: Vec<f32> = features
+        let stats: Vec<f32> = features
             .iter()
-            .map(|&feature| mat[cell_id][feature])
+            .zip(priors.iter())
+            .map(|(&feature, &weight)| mat.get(cell_id, feature) * weight)
             .collect();
 
         let norm: f32 = stats.iter().sum();
-        if norm == 0.0 {
-            let mut rng = rand::thread_rng();
-            return Ok(features[rng.gen_range(0, features.len())]);
+        if norm > 0.0 {
+            return stats.iter().map(|x| x / norm).collect();
         }
 
-        let mut cum_sum_iter = stats.iter_mut().scan(0.0_f32, |cusum, x| {
-            *cusum = *cusum + (*x / norm);
-            Some(*cusum)
-        });
-
-        let chosen_index = cum_sum_iter.position(|x| x > coin_val).unwrap();
-        Ok(features[chosen_index])
+        // no signal in the cell, left with the priors alone
+        let prior_norm: f32 = priors.iter().sum();
+        match prior_norm > 0.0 {
+            true => priors.iter().map(|x| x / prior_norm).collect(),
+            false => uniform,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::multimodal::MultiModalExperiment;
+    use crate::fixtures;
+    use crate::multimodal;
+    use crate::multimodal::{BarcodePolicy, CellIndex, MultiModalExperiment};
     use std::path::Path;
 
     #[test]
     fn test_submatrix() {
-        let ppath = Path::new("test/pivot");
-        let spath = Path::new("test/sec");
-        let mm_obj =
-            MultiModalExperiment::from_paths(vec![spath.to_path_buf(), ppath.to_path_buf()]);
+        let mm_obj = fixtures::experiment();
+
+        let cells = vec![0, 2, 4];
+        let sub_mat = mm_obj.get_submatrix(&mm_obj.cell_index(1, Some(&cells)), &[0, 3], 1);
+        let dense: Vec<Vec<f32>> = (0..sub_mat.num_rows())
+            .map(|row| (0..2).map(|col| sub_mat.get(row, col)).collect())
+            .collect();
+        assert_eq!(dense, vec![vec![1.0, 0.0], vec![1.0, 8.0], vec![0.0, 1.0]]);
+        assert_eq!(sub_mat.nnz(), 4);
+
+        let cells = vec![2, 2];
+        let sub_mat = mm_obj.get_submatrix(&mm_obj.cell_index(1, Some(&cells)), &[3], 1);
+        assert_eq!(sub_mat.get(0, 0), 8.0);
+        assert_eq!(sub_mat.get(1, 0), 8.0);
+
+        let sub_mat = mm_obj.get_submatrix(&mm_obj.cell_index(1, None), &[2], 1);
+        assert_eq!(sub_mat.num_rows(), 5);
+        assert_eq!(sub_mat.get(2, 0), 2.0);
+        assert_eq!(sub_mat.get(3, 0), 0.0);
+    }
 
-        let sub_mat = mm_obj.get_dense_submatrix(Some(&vec![0, 2, 4]), &vec![0, 3], true);
+    #[test]
+    fn test_cell_index() {
+        let cells = vec![4, 1, 4];
+        let cell_index = CellIndex::new(5, Some(&cells));
+        assert_eq!(cell_index.len(), 3);
+        assert_eq!(cell_index.to_global(0), 4);
+        assert_eq!(cell_index.to_global(1), 1);
+        assert_eq!(cell_index.rows[&4], vec![0, 2]);
+
+        let cell_index = CellIndex::new(5, None);
+        assert_eq!(cell_index.len(), 5);
+        assert_eq!(cell_index.to_global(3), 3);
+    }
+
+    #[test]
+    fn test_normalize_barcode() {
+        for barcode in ["AAACAGCCAAGGAATC-1", "AAACAGCCAAGGAATC"] {
+            assert_eq!(multimodal::normalize_barcode(barcode), "AAACAGCCAAGGAATC-1");
+        }
+        for barcode in [
+            "AAACAGCCAAGGAATC-2",
+            "AAACAGCCAAGGAATC_1",
+            "atac#AAACAGCCAAGGAATC-1",
+        ] {
+            assert_ne!(multimodal::normalize_barcode(barcode), "AAACAGCCAAGGAATC-1");
+        }
+        assert_eq!(multimodal::normalize_barcode("cell_7"), "cell_7-1");
+
+        for barcode in [
+            "AAACAGCCAAGGAATC-1",
+            "AAACAGCCAAGGAATC_1",
+            "AAACAGCCAAGGAATC",
+            "atac#AAACAGCCAAGGAATC-1",
+            "CAT_AAACAGCCAAGGAATC_1",
+        ] {
+            assert_eq!(multimodal::loose_barcode(barcode), "AAACAGCCAAGGAATC-1");
+        }
         assert_eq!(
-            sub_mat,
-            vec![vec![1.0, 0.0], vec![1.0, 8.0], vec![0.0, 1.0]]
+            multimodal::loose_barcode("AAACAGCCAAGGAATC-2"),
+            "AAACAGCCAAGGAATC-2"
         );
+        assert_eq!(multimodal::loose_barcode("cell_7"), "cell_7");
     }
 
     #[test]
-    fn test_mmexp() {
-        let ppath = Path::new("test/pivot");
+    fn test_reconcile_barcodes() {
         let spath = Path::new("test/sec");
-        let mm_obj =
+        let ppath = Path::new("test/pivot");
+        let rpath = Path::new("test/pivot_reordered");
+        let paired_obj =
             MultiModalExperiment::from_paths(vec![spath.to_path_buf(), ppath.to_path_buf()]);
+        let load =
+            || MultiModalExperiment::from_paths(vec![spath.to_path_buf(), rpath.to_path_buf()]);
+
+        let mut mm_obj = load();
+        mm_obj
+            .reconcile_barcodes(BarcodePolicy::Unpaired, false)
+            .unwrap();
+        assert!(!mm_obj.is_paired(0, 1));
+        assert!(mm_obj
+            .reconcile_barcodes(BarcodePolicy::Error, true)
+            .is_err());
+
+        // the prefixed barcodes only match when asked to
+        let mut mm_obj = load();
+        mm_obj
+            .reconcile_barcodes(BarcodePolicy::Intersect, false)
+            .unwrap();
+        assert_eq!(mm_obj.num_cells(1), 0);
+
+        // the reordered pivot misses the second cell and has an extra one
+        let mut mm_obj = load();
+        mm_obj
+            .reconcile_barcodes(BarcodePolicy::Intersect, true)
+            .unwrap();
+        assert!(mm_obj.is_paired(0, 1));
+        assert_eq!(mm_obj.num_cells(1), 4);
+        assert_eq!(mm_obj.cells(1)[1], "AAACAGCCAATGCGCT-1");
+
+        let cells = vec![0, 2, 3, 4];
+        let features = vec![0, 1, 2, 3];
+        for assay in 0..2 {
+            assert_eq!(
+                mm_obj.get_submatrix(&mm_obj.cell_index(assay, None), &features, assay),
+                paired_obj.get_submatrix(
+                    &paired_obj.cell_index(assay, Some(&cells)),
+                    &features,
+                    assay
+                )
+            );
+        }
+
+        let mut mm_obj = load();
+        mm_obj
+            .reconcile_barcodes(BarcodePolicy::Union, true)
+            .unwrap();
+        assert_eq!(mm_obj.num_cells(0), 6);
+        assert_eq!(mm_obj.cells(0)[5], "rna#GGGGGGGGGGGGGGGG_1");
 
-        assert_eq!(mm_obj.get_feature_string(true, 0), "FAM138A");
-        assert_eq!(mm_obj.num_cells(), 5);
+        let sec_mat = mm_obj.get_submatrix(&mm_obj.cell_index(0, None), &features, 0);
+        let pivot_mat = mm_obj.get_submatrix(&mm_obj.cell_index(1, None), &features, 1);
+        assert_eq!(pivot_mat.get(1, 3), 0.0);
+        assert_eq!(pivot_mat.get(2, 3), 8.0);
+        assert_eq!(pivot_mat.get(5, 0), 7.0);
+        assert_eq!((0..4).map(|x| sec_mat.get(5, x)).sum::<f32>(), 0.0);
+    }
+
+    #[test]
+    fn test_mmexp() {
+        let mm_obj = fixtures::experiment();
+
+        assert_eq!(mm_obj.get_feature_string(1, 0), "FAM138A");
+        assert_eq!(mm_obj.num_cells(0), 5);
+        assert!(mm_obj.is_paired(0, 1));
         assert_eq!(mm_obj.len(), 2);
-        assert_eq!(mm_obj._pivot(), 0);
+        assert_eq!(mm_obj.pivot(), 1);
+        assert_eq!(mm_obj.chain(), vec![0, 1]);
+        assert_eq!(mm_obj.assay_index("1"), Some(1));
+        assert_eq!(mm_obj.assay_index("2"), None);
+
+        let mut named = MultiModalExperiment::from_named_paths(
+            vec![
+                Path::new("test/sec").to_path_buf(),
+                Path::new("test/pivot").to_path_buf(),
+            ],
+            vec!["atac".to_string(), "rna".to_string()],
+        );
+        assert_eq!(named.assay_index("rna"), Some(1));
+        assert_eq!(named.names(), &vec!["atac", "rna"]);
+        named.set_pivot(0);
+        assert_eq!(named.chain(), vec![1, 0]);
 
         assert_eq!(
             *mm_obj.features()[0],
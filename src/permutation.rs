@@ -0,0 +1,283 @@
+use std::error::Error;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use crate::configs;
+use crate::gibbs;
+use crate::links;
+use crate::multimodal;
+
+// Maps every pivot cell to a randomly chosen pivot cell, either over all the
+// cells or only among the cells of the same microcluster.
+pub fn permute_cells(
+    num_cells: usize,
+    links_obj: &links::Links<f32>,
+    within_microclusters: bool,
+    rng: &mut StdRng,
+) -> Vec<usize> {
+    let mut permutation: Vec<usize> = (0..num_cells).collect();
+    match within_microclusters {
+        false => permutation.shuffle(rng),
+        true => {
+            // iterating in a fixed order to keep the permutation reproducible
+            let microclusters = links_obj.microcluster().unwrap();
+            let mut keys: Vec<&String> = microclusters.keys().collect();
+            keys.sort();
+
+            for key in keys {
+                let cells = &microclusters[key];
+                let mut shuffled = cells.clone();
+                shuffled.shuffle(rng);
+                for (&cell, &target) in cells.iter().zip(shuffled.iter()) {
+                    permutation[cell] = target;
+                }
+            }
+        }
+    }
+
+    permutation
+}
+
+// Empirical p-value of every link of a region, estimated from the gamma of the
+// region recomputed after breaking the sec-pivot cell correspondence.
+pub fn process_region(
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+    config: &configs::GibbsConfig,
+    links_obj: &links::Links<f32>,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    cells: Option<&[usize]>,
+    observed: &gibbs::Gamma,
+) -> Result<Vec<f32>, Box<dyn Error>> {
+    let seed = |replicate| {
+        gibbs::stream_seed(
+            config.seed,
+            "permutation",
+            replicate,
+            links_obj,
+            sec_feats,
+            pivot_feats,
+        )
+    };
+    let mut rng = StdRng::seed_from_u64(seed(0));
+
+    let mut num_extreme = vec![0_usize; observed.values().len()];
+    for replicate in 0..config.num_permutations {
+        let permutation = permute_cells(
+            mm_obj.num_cells(links_obj.pivot_assay()),
+            links_obj,
+            config.permute_within_microclusters,
+            &mut rng,
+        );
+
+        let replicate_config = configs::GibbsConfig {
+            seed: seed(replicate + 1),
+            cell_attribution: false,
+            ..config.clone()
+        };
+        let gamma = gibbs::estimate_region(
+            sec_feats,
+            pivot_feats,
+            &replicate_config,
+            links_obj,
+            mm_obj,
+            cells,
+            Some(&permutation),
+        )?;
+
+        for (index, (null, obs)) in gamma.values().iter().zip(observed.values()).enumerate() {
+            if null >= obs {
+                num_extreme[index] += 1;
+            }
+        }
+    }
+
+    let norm = (config.num_permutations + 1) as f32;
+    Ok(num_extreme
+        .into_iter()
+        .map(|x| (x + 1) as f32 / norm)
+        .collect())
+}
+
+pub fn benjamini_hochberg(pvalues: &[f32]) -> Vec<f32> {
+    let num_tests = pvalues.len();
+    let mut order: Vec<usize> = (0..num_tests).collect();
+    order.sort_by(|&a, &b| pvalues[a].partial_cmp(&pvalues[b]).unwrap());
+
+    // running minimum from the largest p-value down keeps q-values monotone
+    let mut qvalues = vec![1.0_f32; num_tests];
+    let mut running_min = 1.0_f32;
+    for (rank, &index) in order.iter().enumerate().rev() {
+        let qvalue = pvalues[index] * num_tests as f32 / (rank + 1) as f32;
+        running_min = running_min.min(qvalue);
+        qvalues[index] = running_min;
+    }
+
+    qvalues
+}
+
+// Adjusts the p-values of all the reported links, i.e. with a nonzero gamma,
+// jointly across the regions.
+pub fn set_qvalues(gammas: &mut [&mut gibbs::Gamma]) {
+    let mut tests = Vec::new();
+    let mut pvalues = Vec::new();
+    for (gamma_index, gamma) in gammas.iter().enumerate() {
+        let values = gamma.values();
+        for (index, pvalue) in gamma.pvalues().unwrap().iter().enumerate() {
+            if values[index] > 0.0 {
+                tests.push((gamma_index, index));
+                pvalues.push(*pvalue);
+            }
+        }
+    }
+
+    let adjusted = benjamini_hochberg(&pvalues);
+    let mut qvalues: Vec<Vec<f32>> = gammas
+        .iter()
+        .map(|gamma| vec![1.0_f32; gamma.values().len()])
+        .collect();
+    for ((gamma_index, index), qvalue) in tests.into_iter().zip(adjusted) {
+        qvalues[gamma_index][index] = qvalue;
+    }
+
+    for (gamma, qvalues) in gammas.iter_mut().zip(qvalues) {
+        gamma.set_qvalues(qvalues);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+
+    use crate::configs::GibbsConfig;
+    use crate::fixtures;
+    use crate::gibbs;
+    use crate::permutation;
+
+    #[test]
+    fn test_benjamini_hochberg() {
+        let pvalues = vec![0.01, 0.04, 0.03, 0.5];
+        let qvalues = permutation::benjamini_hochberg(&pvalues);
+        let expected = [0.04, 0.053333, 0.053333, 0.5];
+
+        for (x, y) in qvalues.iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-5);
+        }
+
+        // in order 0.005 * 6 / 1, 0.01 * 6 / 2, 0.02 * 6 / 3, 0.04 * 6 / 4,
+        // 0.045 * 6 / 5 and 0.06 * 6 / 6, the fourth capped by the fifth
+        let pvalues = vec![0.04, 0.06, 0.005, 0.045, 0.02, 0.01];
+        let qvalues = permutation::benjamini_hochberg(&pvalues);
+        let expected = [0.054, 0.06, 0.03, 0.054, 0.04, 0.03];
+
+        assert_eq!(qvalues.len(), expected.len());
+        for (x, y) in qvalues.iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_planted_association() {
+        // the first two peaks and genes share the same halves of the cells, while
+        // the last two peaks split the cells in odd and even ones
+        let mm_obj = fixtures::paired("test/planted/sec", "test/planted/pivot");
+        let links_obj = fixtures::links(&mm_obj, "test/planted/links.tsv");
+        let config = GibbsConfig {
+            exact_max_states: 4,
+            num_permutations: 19,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+
+        let pvalues = |pivot_feats: Vec<usize>| {
+            let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+            let observed = gibbs::estimate_region(
+                &sec_feats,
+                &pivot_feats,
+                &config,
+                &links_obj,
+                &mm_obj,
+                None,
+                None,
+            )
+            .unwrap();
+            let pvalues = permutation::process_region(
+                &sec_feats,
+                &pivot_feats,
+                &config,
+                &links_obj,
+                &mm_obj,
+                None,
+                &observed,
+            )
+            .unwrap();
+            (observed.values().clone(), pvalues)
+        };
+
+        let (planted, planted_pvalues) = pvalues(vec![0, 1]);
+        for (x, y) in planted.iter().zip([0.5, 0.0, 0.0, 0.5].iter()) {
+            assert!((x - y).abs() < 1e-3);
+        }
+        assert!(planted_pvalues[0] <= 0.1);
+        assert!(planted_pvalues[3] <= 0.1);
+        assert_eq!(planted_pvalues[1], 1.0);
+        assert_eq!(planted_pvalues[2], 1.0);
+
+        let (independent, independent_pvalues) = pvalues(vec![2, 3]);
+        assert!(independent.iter().all(|x| (x - 0.25).abs() < 1e-3));
+        assert!(independent_pvalues.iter().all(|&x| x > 0.2));
+    }
+
+    #[test]
+    fn test_permutation() {
+        let mm_obj = fixtures::experiment();
+        let mut links_obj = fixtures::links(&mm_obj, "test/olaps.tsv");
+
+        let mut clusters = HashMap::new();
+        clusters.insert("a".to_string(), vec![0, 2]);
+        clusters.insert("b".to_string(), vec![1, 3, 4]);
+        links_obj.set_microclusters(clusters);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut cells = permutation::permute_cells(5, &links_obj, true, &mut rng);
+        assert!(cells[0] == 0 || cells[0] == 2);
+        assert!(cells[4] != 0 && cells[4] != 2);
+        cells.sort();
+        assert_eq!(cells, vec![0, 1, 2, 3, 4]);
+
+        let pivot_feats = vec![0, 1, 3];
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let config = GibbsConfig {
+            num_permutations: 9,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+
+        let observed = gibbs::estimate_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            None,
+            None,
+        )
+        .unwrap();
+        let pvalues = permutation::process_region(
+            &sec_feats,
+            &pivot_feats,
+            &config,
+            &links_obj,
+            &mm_obj,
+            None,
+            &observed,
+        )
+        .unwrap();
+
+        assert_eq!(pvalues.len(), observed.values().len());
+    }
+}
@@ -0,0 +1,303 @@
+use clap::ArgMatches;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::carina;
+use crate::gibbs;
+use crate::links;
+use crate::unify;
+
+// An independently quantifiable region along with its links, identified as in
+// the gamma output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub id: String,
+    pub sec_feats: Vec<usize>,
+    pub pivot_feats: Vec<usize>,
+    pub links: Vec<(usize, usize)>,
+}
+
+impl Region {
+    pub fn new(links_obj: &links::Links<f32>, pivot_feats: Vec<usize>) -> Region {
+        let sec_feats = links_obj.get_from_pivot_hits(&pivot_feats);
+        let mut links = Vec::new();
+        for &pivot in pivot_feats.iter() {
+            for &sec in links_obj.entry_from_pivot(pivot) {
+                links.push((sec, pivot));
+            }
+        }
+        links.sort();
+
+        Region {
+            id: gibbs::region_id(links_obj, &sec_feats, &pivot_feats),
+            sec_feats,
+            pivot_feats,
+            links,
+        }
+    }
+
+    // number of sec and pivot features, as capped by --max-region-size
+    pub fn size(&self) -> usize {
+        self.sec_feats.len() + self.pivot_feats.len()
+    }
+
+    pub fn contains(&self, links_obj: &links::Links<f32>, name: &str) -> bool {
+        self.sec_feats
+            .iter()
+            .any(|&x| links_obj.feature_string(false, x) == name)
+            || self
+                .pivot_feats
+                .iter()
+                .any(|&x| links_obj.feature_string(true, x) == name)
+    }
+
+    fn write(
+        &self,
+        ofile: &mut BufWriter<File>,
+        links_obj: &links::Links<f32>,
+    ) -> Result<(), Box<dyn Error>> {
+        let names = |feats: &Vec<usize>, is_pivot: bool| {
+            feats
+                .iter()
+                .map(|&x| links_obj.feature_string(is_pivot, x))
+                .collect::<Vec<&str>>()
+                .join(",")
+        };
+
+        writeln!(
+            ofile,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.id,
+            self.size(),
+            self.sec_feats.len(),
+            self.pivot_feats.len(),
+            self.links.len(),
+            self.sec_feats.len() * self.pivot_feats.len(),
+            names(&self.sec_feats, false),
+            names(&self.pivot_feats, true),
+        )?;
+
+        Ok(())
+    }
+
+    // Bipartite subgraph of the region in Graphviz DOT, sec features as
+    // ellipses and pivot features as boxes, edges labelled by their weights.
+    pub fn write_dot<W: Write>(
+        &self,
+        ofile: &mut W,
+        links_obj: &links::Links<f32>,
+    ) -> Result<(), Box<dyn Error>> {
+        let quote = |is_pivot: bool, x: usize| {
+            format!(
+                "\"{}\"",
+                links_obj.feature_string(is_pivot, x).replace('"', "\\\"")
+            )
+        };
+
+        writeln!(ofile, "graph \"{}\" {{", self.id)?;
+        writeln!(ofile, "  rankdir=LR;")?;
+        for &sec in self.sec_feats.iter() {
+            writeln!(ofile, "  {} [shape=ellipse];", quote(false, sec))?;
+        }
+        for &pivot in self.pivot_feats.iter() {
+            writeln!(ofile, "  {} [shape=box];", quote(true, pivot))?;
+        }
+        for &(sec, pivot) in self.links.iter() {
+            write!(ofile, "  {} -- {}", quote(false, sec), quote(true, pivot))?;
+            match links_obj.has_weights() {
+                true => writeln!(ofile, " [label=\"{}\"];", links_obj.weight(sec, pivot))?,
+                false => writeln!(ofile, ";")?,
+            }
+        }
+        writeln!(ofile, "}}")?;
+
+        Ok(())
+    }
+}
+
+pub fn describe(links_obj: &links::Links<f32>, regions: &links::IQRegions) -> Vec<Region> {
+    regions
+        .groups()
+        .iter()
+        .map(|x| Region::new(links_obj, x.clone()))
+        .collect()
+}
+
+// Number of regions per size, in power of two bins given as (lo, hi, count).
+pub fn size_histogram(regions: &[Region]) -> Vec<(usize, usize, usize)> {
+    let max_size = regions.iter().map(|x| x.size()).max().unwrap_or(0);
+    let mut bins = Vec::new();
+    let mut hi = 2;
+    while hi / 2 < max_size {
+        bins.push((hi / 2 + 1, hi, 0));
+        hi *= 2;
+    }
+
+    for region in regions.iter() {
+        let bin = bins
+            .iter_mut()
+            .find(|x| region.size() <= x.1)
+            .expect("region size out of the bins");
+        bin.2 += 1;
+    }
+    bins
+}
+
+fn size_range(lo: usize, hi: usize) -> String {
+    match lo == hi {
+        true => lo.to_string(),
+        false => format!("{}-{}", lo, hi),
+    }
+}
+
+fn log_histogram(bins: &[(usize, usize, usize)]) {
+    let max_count = bins.iter().map(|x| x.2).max().unwrap_or(0);
+
+    info!("Region sizes:");
+    for &(lo, hi, count) in bins {
+        let bar = match max_count {
+            0 => 0,
+            _ => (count * 50).div_ceil(max_count),
+        };
+        info!("{}\t{}\t{}", size_range(lo, hi), count, "#".repeat(bar));
+    }
+}
+
+pub fn write_histogram<W: Write>(
+    ofile: &mut W,
+    bins: &[(usize, usize, usize)],
+) -> Result<(), Box<dyn Error>> {
+    writeln!(ofile, "size\tregions")?;
+    for &(lo, hi, count) in bins {
+        writeln!(ofile, "{}\t{}", size_range(lo, hi), count)?;
+    }
+
+    Ok(())
+}
+
+pub fn write(
+    mut ofile: BufWriter<File>,
+    regions: &[Region],
+    links_obj: &links::Links<f32>,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        ofile,
+        "region_id\tsize\tnum_secs\tnum_pivots\tnum_links\tnum_states\tsecs\tpivots"
+    )?;
+    for region in regions.iter() {
+        region.write(&mut ofile, links_obj)?;
+    }
+
+    Ok(())
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mm_obj = unify::read_experiment(sub_m)?;
+    let (chain, links_paths) = unify::read_chain(sub_m, &mm_obj)?;
+    let mut report = unify::skip_report(sub_m);
+    let orientation = unify::orientation(sub_m);
+
+    for (hop, links_path) in links_paths.into_iter().enumerate() {
+        let (sec, pivot) = (chain[hop], chain[hop + 1]);
+
+        info!("Creating Link object");
+        let mut links_obj =
+            links::Links::new(&mm_obj, links_path, sec, pivot, orientation, &mut report)?;
+        info!("{:?}", links_obj);
+
+        if let Some(max_size) = sub_m.value_of("maxregion") {
+            let num_dropped = links_obj.limit_region_size(max_size.parse::<usize>()?);
+            info!("Dropped {} links to limit the region size", num_dropped);
+        }
+
+        info!("Finding Independantly quantifiable regions");
+        let regions = describe(&links_obj, &links_obj.extract_iqr()?);
+        info!("Found total {:?} regions", regions.len());
+
+        // every link of a longer chain gets its own outputs
+        let hop_name = match chain.len() > 2 {
+            true => Some(links_obj.name()),
+            false => None,
+        };
+        let writer = |arg: &str| match &hop_name {
+            Some(name) => carina::file::bufwriter_from_clap_with_suffix(sub_m, arg, name),
+            None => carina::file::bufwriter_from_clap(sub_m, arg),
+        };
+        write(writer("output")?, &regions, &links_obj)?;
+
+        let bins = size_histogram(&regions);
+        log_histogram(&bins);
+        if sub_m.is_present("histogram") {
+            write_histogram(&mut writer("histogram")?, &bins)?;
+        }
+
+        // the requested region, by id or by any of its features, or else the
+        // largest one
+        if sub_m.is_present("dot") {
+            let region = match sub_m.value_of("region") {
+                Some(query) => regions
+                    .iter()
+                    .find(|x| x.id == query || x.contains(&links_obj, query)),
+                None => regions.iter().max_by_key(|x| x.size()),
+            };
+
+            match region {
+                Some(region) => {
+                    info!("Writing region {} as DOT", region.id);
+                    region.write_dot(&mut writer("dot")?, &links_obj)?;
+                }
+                None => warn!("No region matching {:?}", sub_m.value_of("region")),
+            }
+        }
+    }
+
+    unify::write_skip_report(sub_m, &report)?;
+    info!("All done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures;
+    use crate::regions;
+
+    #[test]
+    fn test_regions() {
+        let mm_obj = fixtures::experiment();
+        let mut links_obj = fixtures::links(&mm_obj, "test/olaps_weighted.tsv");
+
+        let regions = regions::describe(&links_obj, &links_obj.extract_iqr().unwrap());
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].pivot_feats, vec![0, 1, 3]);
+        assert_eq!(regions[0].sec_feats, vec![0, 6, 7]);
+        assert_eq!(
+            regions[0].links,
+            vec![(0, 0), (0, 1), (6, 3), (7, 1), (7, 3)]
+        );
+        assert_eq!(regions[1].size(), 6);
+        assert!(regions[0].contains(&links_obj, "OR4F16"));
+        assert!(!regions[1].contains(&links_obj, "OR4F16"));
+        let bins = regions::size_histogram(&regions);
+        assert_eq!(bins, vec![(2, 2, 0), (3, 4, 0), (5, 8, 2)]);
+
+        let mut histogram = Vec::new();
+        regions::write_histogram(&mut histogram, &bins).unwrap();
+        assert_eq!(
+            String::from_utf8(histogram).unwrap(),
+            "size\tregions\n2\t0\n3-4\t0\n5-8\t2\n"
+        );
+
+        let mut dot = Vec::new();
+        regions[0].write_dot(&mut dot, &links_obj).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with(&format!("graph \"{}\" {{\n", regions[0].id)));
+        assert!(dot.contains("  \"chr1-10126-10439\" -- \"OR4F5\" [label=\"2.5\"];\n"));
+        assert_eq!(dot.matches(" -- ").count(), 5);
+
+        links_obj.limit_region_size(4);
+        let limited = regions::describe(&links_obj, &links_obj.extract_iqr().unwrap());
+        assert!(limited.iter().all(|x| x.size() <= 4));
+        assert_eq!(regions::size_histogram(&limited).len(), 2);
+    }
+}
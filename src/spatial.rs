@@ -4,7 +4,6 @@ use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 
 use clap::ArgMatches;
-use sce;
 
 use crossbeam::queue::ArrayQueue;
 use indicatif::{ProgressBar, ProgressStyle};
@@ -14,13 +13,13 @@ pub fn get_moransi(
     weights: &sce::SingleCellExperiment<f32>,
     values: &sce::SingleCellExperiment<f32>,
     row_index: usize,
-    row_sums: &Vec<f32>,
+    row_sums: &[f32],
 ) -> Result<f32, Box<dyn Error>> {
     let n = values.cols();
     let val_it = values.counts().outer_iterator().nth(row_index).unwrap();
 
     let mut x_sum = 0.0;
-    let mut x = vec![0.0 as f32; n];
+    let mut x = vec![0.0_f32; n];
     for (col_ind, &val) in val_it.iter() {
         x_sum += val;
         x[col_ind] = val;
@@ -30,8 +29,8 @@ pub fn get_moransi(
         return Ok(0.0);
     }
 
-    let x_mean = x_sum as f32 / n as f32;
-    let z: Vec<f32> = x.iter().map(|&x| x as f32 - x_mean).collect();
+    let x_mean = x_sum / n as f32;
+    let z: Vec<f32> = x.iter().map(|&x| x - x_mean).collect();
     let v: f32 = z.iter().map(|x| x * x).sum();
 
     let mut w = 0.0;
@@ -51,13 +50,13 @@ pub fn get_gearyc(
     weights: &sce::SingleCellExperiment<f32>,
     values: &sce::SingleCellExperiment<f32>,
     row_index: usize,
-    row_sums: &Vec<f32>,
+    row_sums: &[f32],
 ) -> Result<f32, Box<dyn Error>> {
     let n = values.cols();
     let val_it = values.counts().outer_iterator().nth(row_index).unwrap();
 
     let mut x_sum = 0.0;
-    let mut x = vec![0.0 as f32; n];
+    let mut x = vec![0.0_f32; n];
     for (col_ind, &val) in val_it.iter() {
         x_sum += val;
         x[col_ind] = val;
@@ -67,8 +66,8 @@ pub fn get_gearyc(
         return Ok(1.0);
     }
 
-    let x_mean = x_sum as f32 / n as f32;
-    let z: Vec<f32> = x.iter().map(|&x| x as f32 - x_mean).collect();
+    let x_mean = x_sum / n as f32;
+    let z: Vec<f32> = x.iter().map(|&x| x - x_mean).collect();
     let v: f32 = z.iter().map(|x| x * x).sum();
 
     let mut w = 0.0;
@@ -91,6 +90,7 @@ pub fn process(
     values: &sce::SingleCellExperiment<f32>,
     mut ofile: BufWriter<File>,
     is_moransi: bool,
+    num_threads: usize,
 ) -> Result<(), Box<dyn Error>> {
     let num_values = values.rows();
     let pbar = ProgressBar::new(num_values as u64);
@@ -115,7 +115,6 @@ pub fn process(
         })
         .collect();
 
-    let num_threads = 10;
     let q = Arc::new(ArrayQueue::<usize>::new(num_values));
     let arc_row_sums = Arc::new(row_sums);
     (0..num_values).for_each(|x| q.push(x).unwrap());
@@ -131,9 +130,9 @@ pub fn process(
                 match reader.pop() {
                     Some(index) => {
                         let stats = match is_moransi {
-                            true => get_moransi(&weights, &values, index, &row_sums)
+                            true => get_moransi(weights, values, index, &row_sums)
                                 .expect("can't process rows"),
-                            false => get_gearyc(&weights, &values, index, &row_sums)
+                            false => get_gearyc(weights, values, index, &row_sums)
                                 .expect("can't process rows"),
                         };
                         tx.send(Some((index, stats)))
@@ -153,7 +152,7 @@ pub fn process(
             match out_data {
                 Some((index, stats)) => {
                     pbar.inc(1);
-                    write!(ofile, "{}\t{}\n", column_names[index], stats)
+                    writeln!(ofile, "{}\t{}", column_names[index], stats)
                         .expect("can't write to file");
                 } // end-Some
                 None => {
@@ -164,10 +163,10 @@ pub fn process(
                         // consume what's remaining
                         for out_data in rx.iter() {
                             pbar.inc(1);
-                            out_data.map_or((), |(index, stats)| {
-                                write!(ofile, "{}\t{}\n", column_names[index], stats)
+                            if let Some((index, stats)) = out_data {
+                                writeln!(ofile, "{}\t{}", column_names[index], stats)
                                     .expect("can't write to file");
-                            });
+                            }
                         }
 
                         break;
@@ -187,6 +186,7 @@ pub fn generate_stats(
     values_file_path: PathBuf,
     ofile: BufWriter<File>,
     method: Option<&str>,
+    num_threads: usize,
 ) -> Result<(), Box<dyn Error>> {
     let wt_mat: sce::SingleCellExperiment<f32> =
         sce::SingleCellExperiment::from_tenx_v2(weights_file_path)?;
@@ -199,11 +199,11 @@ pub fn generate_stats(
     match method {
         Some("Moransi") => {
             info!("Starting Moran's I");
-            process(&wt_mat, &val_mat, ofile, true)?;
+            process(&wt_mat, &val_mat, ofile, true, num_threads)?;
         }
         Some("Gearyc") => {
             info!("Starting Moran's I");
-            process(&wt_mat, &val_mat, ofile, false)?;
+            process(&wt_mat, &val_mat, ofile, false, num_threads)?;
         }
         _ => unreachable!(),
     };
@@ -215,12 +215,15 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let weights_file_path = carina::file::file_path_from_clap(sub_m, "weights")?;
     let values_file_path = carina::file::file_path_from_clap(sub_m, "values")?;
     let ofile = carina::file::bufwriter_from_clap(sub_m, "output")?;
+    let num_threads = sub_m.value_of("threads").unwrap().parse::<usize>()?;
+    assert!(num_threads > 0, "need at least one thread");
 
     generate_stats(
         weights_file_path,
         values_file_path,
         ofile,
         sub_m.value_of("method"),
+        num_threads,
     )?;
 
     info!("All done");
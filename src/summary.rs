@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::gibbs;
+use crate::links;
+
+// Marginal view of a single feature within its region, the mass is the share
+// of the region's gamma and the entropy (in nats) is the one of the
+// distribution over its partners conditioned on the feature. As the gamma of
+// every region sums to one, the masses only compare within a region, the
+// region size (its number of sec and pivot feature pairs) is kept along to
+// put them in scale across regions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureSummary {
+    pub feature: usize,
+    pub region_id: String,
+    pub region_size: usize,
+    pub mass: f32,
+    pub num_links: usize,
+    pub entropy: f32,
+    pub partner: Option<(usize, f32)>,
+}
+
+impl FeatureSummary {
+    fn new(
+        feature: usize,
+        region_id: &str,
+        region_size: usize,
+        partners: Vec<(usize, f32)>,
+    ) -> FeatureSummary {
+        let mass: f32 = partners.iter().map(|x| x.1).sum();
+        let num_links = partners.iter().filter(|x| x.1 > 0.0).count();
+        let entropy = partners
+            .iter()
+            .filter(|x| x.1 > 0.0)
+            .map(|x| {
+                let p = x.1 / mass;
+                p * (1.0 / p).ln()
+            })
+            .fold(0.0_f32, |acc, x| acc + x);
+        let partner = partners.into_iter().filter(|x| x.1 > 0.0).fold(
+            None,
+            |best: Option<(usize, f32)>, x| match best {
+                Some(best) if best.1 >= x.1 => Some(best),
+                _ => Some(x),
+            },
+        );
+
+        FeatureSummary {
+            feature,
+            region_id: region_id.to_string(),
+            region_size,
+            mass,
+            num_links,
+            entropy,
+            partner,
+        }
+    }
+
+    fn write(
+        &self,
+        ofile: &mut BufWriter<File>,
+        links_obj: &links::Links<f32>,
+        is_pivot: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let (partner, partner_gamma) = match self.partner {
+            Some((partner, val)) => (
+                links_obj.feature_string(!is_pivot, partner),
+                val.to_string(),
+            ),
+            None => ("NA", "NA".to_string()),
+        };
+
+        writeln!(
+            ofile,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            links_obj.feature_string(is_pivot, self.feature),
+            self.region_id,
+            self.region_size,
+            self.mass,
+            self.num_links,
+            self.entropy,
+            partner,
+            partner_gamma,
+        )?;
+
+        Ok(())
+    }
+}
+
+// per feature summaries of both the assays, over all the regions
+pub struct Summaries {
+    secs: Vec<FeatureSummary>,
+    pivots: Vec<FeatureSummary>,
+}
+
+impl Summaries {
+    pub fn new() -> Summaries {
+        Summaries {
+            secs: Vec::new(),
+            pivots: Vec::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        gamma: &gibbs::Gamma,
+        sec_feats: &[usize],
+        pivot_feats: &[usize],
+        region_id: &str,
+    ) {
+        let num_pivots = pivot_feats.len();
+        let region_size = sec_feats.len() * num_pivots;
+        let values = gamma.values();
+        let value =
+            |sec_index: usize, pivot_index: usize| values[sec_index * num_pivots + pivot_index];
+
+        for (sec_index, &sec) in sec_feats.iter().enumerate() {
+            let partners = pivot_feats
+                .iter()
+                .enumerate()
+                .map(|(pivot_index, &pivot)| (pivot, value(sec_index, pivot_index)))
+                .collect();
+            self.secs
+                .push(FeatureSummary::new(sec, region_id, region_size, partners));
+        }
+
+        for (pivot_index, &pivot) in pivot_feats.iter().enumerate() {
+            let partners = sec_feats
+                .iter()
+                .enumerate()
+                .map(|(sec_index, &sec)| (sec, value(sec_index, pivot_index)))
+                .collect();
+            self.pivots
+                .push(FeatureSummary::new(pivot, region_id, region_size, partners));
+        }
+    }
+
+    // one table per assay, rows ordered as the features of the assay
+    pub fn write(
+        &mut self,
+        dir: &Path,
+        links_obj: &links::Links<f32>,
+    ) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(dir)?;
+
+        for (name, summaries, is_pivot) in [
+            ("sec", &mut self.secs, false),
+            ("pivot", &mut self.pivots, true),
+        ] {
+            summaries.sort_by_key(|x| x.feature);
+
+            let path = dir.join(format!("{}_summary.tsv", name));
+            let mut ofile = BufWriter::new(File::create(path)?);
+            let partner = match is_pivot {
+                true => "sec",
+                false => "pivot",
+            };
+            writeln!(
+                ofile,
+                "{}\tregion_id\tregion_size\tmass\tnum_links\tentropy\ttop_{}\ttop_gamma",
+                name, partner
+            )?;
+
+            for summary in summaries.iter() {
+                summary.write(&mut ofile, links_obj, is_pivot)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gibbs::Gamma;
+    use crate::summary::{FeatureSummary, Summaries};
+
+    #[test]
+    fn test_feature_summary() {
+        let summary = FeatureSummary::new(3, "r", 3, vec![(0, 0.1), (4, 0.0), (7, 0.3)]);
+        assert!((summary.mass - 0.4).abs() < 1e-6);
+        assert_eq!(summary.num_links, 2);
+        assert_eq!(summary.partner, Some((7, 0.3)));
+
+        let expected = -(0.25_f32 * 0.25_f32.ln() + 0.75 * 0.75_f32.ln());
+        assert!((summary.entropy - expected).abs() < 1e-6);
+
+        let empty = FeatureSummary::new(3, "r", 1, vec![(0, 0.0)]);
+        assert_eq!(empty.num_links, 0);
+        assert_eq!(empty.entropy, 0.0);
+        assert_eq!(empty.partner, None);
+    }
+    #[test]
+    fn test_summaries() {
+        // the gamma of 2 sec and 3 pivot features is stored sec major
+        let gamma = Gamma::from_probabilities(2, 3, vec![0.1, 0.0, 0.2, 0.3, 0.15, 0.25]);
+        let mut summaries = Summaries::new();
+        summaries.add(&gamma, &[5, 9], &[1, 2, 4], "r");
+
+        let expected = [(5, 0.3, (4, 0.2)), (9, 0.7, (1, 0.3))];
+        for (summary, (feature, mass, partner)) in summaries.secs.iter().zip(expected.iter()) {
+            assert_eq!(summary.feature, *feature);
+            assert!((summary.mass - mass).abs() < 1e-6);
+            assert_eq!(summary.partner, Some(*partner));
+        }
+
+        let expected = [
+            (1, 0.4, (9, 0.3)),
+            (2, 0.15, (9, 0.15)),
+            (4, 0.45, (9, 0.25)),
+        ];
+        for (summary, (feature, mass, partner)) in summaries.pivots.iter().zip(expected.iter()) {
+            assert_eq!(summary.feature, *feature);
+            assert!((summary.mass - mass).abs() < 1e-6);
+            assert_eq!(summary.partner, Some(*partner));
+        }
+        assert!(summaries.secs.iter().all(|x| x.region_size == 6));
+        assert_eq!(summaries.pivots[1].num_links, 1);
+    }
+}
@@ -1,34 +1,252 @@
 use clap::ArgMatches;
 use std::error::Error;
+use std::path::PathBuf;
 
 use crate::carina;
+use crate::configs;
 use crate::gibbs;
 use crate::links;
 use crate::multimodal;
+use crate::walk;
 
-pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+// Matrices of all the modalities, with their barcodes reconciled.
+pub fn read_experiment(
+    sub_m: &ArgMatches,
+) -> Result<multimodal::MultiModalExperiment<f32>, Box<dyn Error>> {
     let ipaths = carina::file::files_path_from_clap(sub_m, "ipaths")?;
     assert!(ipaths.len() > 1, "indus expects at least two matrices");
 
     info!("Reading quant matrices");
-    let mm_obj = multimodal::MultiModalExperiment::from_paths(ipaths);
+    let mut mm_obj = match sub_m.values_of("names") {
+        Some(names) => {
+            let names = names.map(|x| x.to_string()).collect();
+            multimodal::MultiModalExperiment::from_named_paths(ipaths, names)
+        }
+        None => multimodal::MultiModalExperiment::from_paths(ipaths),
+    };
+
+    let policy = match sub_m.value_of("barcodes") {
+        Some("unpaired") => multimodal::BarcodePolicy::Unpaired,
+        Some("intersect") => multimodal::BarcodePolicy::Intersect,
+        Some("union") => multimodal::BarcodePolicy::Union,
+        _ => multimodal::BarcodePolicy::Error,
+    };
+    mm_obj.reconcile_barcodes(policy, sub_m.is_present("loosebarcodes"))?;
     info!("{:?}", mm_obj);
 
+    if let Some(pivot) = sub_m.value_of("pivot") {
+        let pivot = mm_obj.assay_index(pivot).expect("unknown pivot modality");
+        mm_obj.set_pivot(pivot);
+    }
+    info!(
+        "Using {} as the pivot modality",
+        mm_obj.names()[mm_obj.pivot()]
+    );
+
+    Ok(mm_obj)
+}
+
+// Modalities of the chain along with the links files connecting the
+// consecutive ones.
+pub fn read_chain(
+    sub_m: &ArgMatches,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+) -> Result<(Vec<usize>, Vec<PathBuf>), Box<dyn Error>> {
+    let chain: Vec<usize> = match sub_m.values_of("chain") {
+        Some(values) => values
+            .map(|x| {
+                mm_obj
+                    .assay_index(x)
+                    .expect("unknown modality in the chain")
+            })
+            .collect(),
+        None => mm_obj.chain(),
+    };
+    let links_paths = carina::file::files_path_from_clap(sub_m, "links")?;
+    assert!(chain.len() > 1, "the chain needs at least two modalities");
+    assert_eq!(
+        links_paths.len(),
+        chain.len() - 1,
+        "expected one links file per pair of consecutive modalities"
+    );
+
+    Ok((chain, links_paths))
+}
+
+pub fn orientation(sub_m: &ArgMatches) -> links::Orientation {
+    match sub_m.value_of("orientation") {
+        Some("forward") => links::Orientation::Forward,
+        Some("reversed") => links::Orientation::Reversed,
+        _ => links::Orientation::Auto,
+    }
+}
+
+// unknown features and cells fail the run unless asked to skip them
+pub fn skip_report(sub_m: &ArgMatches) -> links::SkipReport {
+    match sub_m.is_present("lenient") {
+        true => links::SkipReport::new(links::Policy::Lenient),
+        false => links::SkipReport::new(links::Policy::Strict),
+    }
+}
+
+pub fn write_skip_report(
+    sub_m: &ArgMatches,
+    report: &links::SkipReport,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(report_path) = sub_m.value_of("lenient") {
+        info!(
+            "Writing {} skipped entries to {}",
+            report.len(),
+            report_path
+        );
+        report.write(PathBuf::from(report_path))?;
+    }
+
+    Ok(())
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mm_obj = read_experiment(sub_m)?;
+    // consecutive modalities of the chain are connected by the links files
+    let (chain, links_paths) = read_chain(sub_m, &mm_obj)?;
+
+    let mut config = configs::GibbsConfig::default();
+    if let Some(min_samples) = sub_m.value_of("minsamples") {
+        config.min_samples = min_samples.parse::<usize>()?;
+    }
+    if let Some(max_samples) = sub_m.value_of("maxsamples") {
+        config.max_samples = max_samples.parse::<usize>()?;
+    }
+    if let Some(samples_per_state) = sub_m.value_of("samplesperstate") {
+        config.samples_per_state = samples_per_state.parse::<usize>()?;
+    }
+    if let Some(mcse) = sub_m.value_of("mcse") {
+        config.target_mcse = Some(mcse.parse::<f32>()?);
+    }
+    assert!(
+        config.min_samples <= config.max_samples,
+        "min samples can't be more than max samples"
+    );
+    if let Some(exact_max_states) = sub_m.value_of("exact") {
+        config.exact_max_states = exact_max_states.parse::<usize>()?;
+    }
+    if let Some(num_bootstraps) = sub_m.value_of("bootstrap") {
+        config.num_bootstraps = num_bootstraps.parse::<usize>()?;
+    }
+    if let Some(ci_level) = sub_m.value_of("cilevel") {
+        config.ci_level = ci_level.parse::<f32>()?;
+        assert!(
+            config.ci_level > 0.0 && config.ci_level < 1.0,
+            "ci level has to be in (0, 1)"
+        );
+    }
+    if let Some(num_permutations) = sub_m.value_of("permutations") {
+        config.num_permutations = num_permutations.parse::<usize>()?;
+    }
+    config.permute_within_microclusters = sub_m.value_of("permute") == Some("microclusters");
+    if config.permute_within_microclusters {
+        assert!(
+            sub_m.is_present("microclusters"),
+            "permuting within microclusters needs the microclusters file"
+        );
+    }
+    if let Some(num_threads) = sub_m.value_of("threads") {
+        config.num_threads = num_threads.parse::<usize>()?;
+        assert!(config.num_threads > 0, "need at least one thread");
+    }
+    if let Some(max_paths) = sub_m.value_of("maxpaths") {
+        config.max_paths = max_paths.parse::<usize>()?;
+    }
+    if let Some(chains) = sub_m.value_of("chains") {
+        config.num_chains = chains.parse::<usize>()?;
+    }
+    if let Some(rhat) = sub_m.value_of("rhat") {
+        config.rhat_threshold = rhat.parse::<f32>()?;
+    }
+    if let Some(burn_in) = sub_m.value_of("burnin") {
+        config.burn_in = burn_in.parse::<usize>()?;
+    }
+    if let Some(thin) = sub_m.value_of("thin") {
+        config.thin = thin.parse::<usize>()?;
+        assert!(config.thin > 0, "thinning interval has to be positive");
+    }
+    match sub_m.value_of("seed") {
+        Some(seed) => config.seed = seed.parse::<u64>()?,
+        None => info!("No seed provided, using {}", config.seed),
+    }
+
+    let outputs = configs::OutputConfig {
+        mtx_dir: sub_m.value_of("mtx").map(PathBuf::from),
+        summary_dir: sub_m.value_of("summary").map(PathBuf::from),
+        attribution_dir: sub_m.value_of("attribution").map(PathBuf::from),
+    };
+    // attributing cells needs the chains, even for the exactly solvable regions
+    config.cell_attribution = outputs.attribution_dir.is_some();
+    info!("{:?}", config);
+
+    let mut report = skip_report(sub_m);
+    let orientation = orientation(sub_m);
+
+    if chain.len() > 2 {
+        walk_chain(
+            sub_m,
+            &mm_obj,
+            &chain,
+            links_paths,
+            &config,
+            orientation,
+            &mut report,
+        )?;
+        write_skip_report(sub_m, &report)?;
+        info!("All done");
+        return Ok(());
+    }
+
+    let (sec, pivot) = (chain[0], chain[1]);
+    let links_path = links_paths.into_iter().next().unwrap();
+
     info!("Creating Link object");
-    let olap_path = carina::file::file_path_from_clap(sub_m, "links")?;
-    let links_obj = match carina::file::try_file_path_from_clap(sub_m, "microclusters") {
-        Some(mpath) => match carina::file::try_file_path_from_clap(sub_m, "anchors") {
-            Some(apath) => {
-                links::Links::new_with_microclusters_and_anchors(&mm_obj, olap_path, mpath, apath)
+    let mut links_obj =
+        links::Links::new(&mm_obj, links_path, sec, pivot, orientation, &mut report)?;
+    if let Some(mpath) = carina::file::try_file_path_from_clap(sub_m, "microclusters") {
+        links_obj.read_microclusters(mpath, &mut report)?;
+    }
+    if let Some(apath) = carina::file::try_file_path_from_clap(sub_m, "anchors") {
+        links_obj.read_anchors(apath, &mut report)?;
+    }
+    info!("{:?}", links_obj);
+
+    // unpaired modalities only meet through the anchors, so the chains can
+    // only be driven by the sec cells having some
+    let anchored = match mm_obj.is_paired(sec, pivot) {
+        true => None,
+        false => {
+            if !links_obj.has_anchors() {
+                return Err(format!(
+                    "unpaired modalities {} and {} need anchors, or --barcodes to pair them",
+                    mm_obj.names()[sec],
+                    mm_obj.names()[pivot]
+                )
+                .into());
             }
-            None => links::Links::new_with_microclusters(&mm_obj, olap_path, mpath),
-        },
-        None => match carina::file::try_file_path_from_clap(sub_m, "anchors") {
-            Some(apath) => links::Links::new_with_anchors(&mm_obj, olap_path, apath),
-            None => links::Links::new(&mm_obj, olap_path),
-        },
+            assert!(
+                !config.permute_within_microclusters,
+                "can't permute within microclusters across unpaired modalities"
+            );
+
+            let cells = links_obj.anchored_cells();
+            let num_unanchored = mm_obj.num_cells(sec) - cells.len();
+            if num_unanchored > 0 {
+                warn!("Leaving out {} sec cells without anchors", num_unanchored);
+            }
+            Some(cells)
+        }
     };
-    info!("{:?}", links_obj);
+
+    if let Some(max_size) = sub_m.value_of("maxregion") {
+        let num_dropped = links_obj.limit_region_size(max_size.parse::<usize>()?);
+        info!("Dropped {} links to limit the region size", num_dropped);
+    }
 
     info!("Finding Independantly quantifiable regions");
     let regions = links_obj.extract_iqr()?;
@@ -38,17 +256,107 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     match links_obj.has_microclusters() {
         false => {
             let ofile = carina::file::bufwriter_from_clap(sub_m, "output")?;
-            gibbs::callback(&mm_obj, &links_obj, &regions, ofile, None)?;
+            gibbs::callback(
+                &mm_obj,
+                &links_obj,
+                &regions,
+                ofile,
+                anchored.as_deref(),
+                &config,
+                &outputs,
+            )?;
         }
         true => {
             for (key, value) in links_obj.microcluster().unwrap() {
                 info!("Working on microcluster {}", key);
+                let cells: Vec<usize> = match &anchored {
+                    Some(anchored) => value
+                        .iter()
+                        .filter(|x| anchored.binary_search(x).is_ok())
+                        .cloned()
+                        .collect(),
+                    None => value.clone(),
+                };
+                if cells.is_empty() {
+                    warn!("Skipping microcluster {} without anchored cells", key);
+                    continue;
+                }
+
                 let ofile = carina::file::bufwriter_from_clap_with_suffix(sub_m, "output", key)?;
-                gibbs::callback(&mm_obj, &links_obj, &regions, ofile, Some(value))?;
+                gibbs::callback(
+                    &mm_obj,
+                    &links_obj,
+                    &regions,
+                    ofile,
+                    Some(&cells),
+                    &config,
+                    &outputs.with_subdir(key),
+                )?;
             }
         }
     }
 
+    write_skip_report(sub_m, &report)?;
     info!("All done");
     Ok(())
 }
+
+// Chains of more than two modalities are sampled as whole paths of links, e.g.
+// peak to gene to protein, which needs the cells paired across all of them and
+// leaves out the extras of the pairwise regions.
+fn walk_chain(
+    sub_m: &ArgMatches,
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    chain: &[usize],
+    links_paths: Vec<PathBuf>,
+    config: &configs::GibbsConfig,
+    orientation: links::Orientation,
+    report: &mut links::SkipReport,
+) -> Result<(), Box<dyn Error>> {
+    let unsupported = [
+        ("anchors", "--anchors"),
+        ("microclusters", "--microclusters"),
+        ("exact", "--exact-max-states"),
+        ("bootstrap", "--bootstrap"),
+        ("permutations", "--permutations"),
+        ("mtx", "--mtx"),
+        ("summary", "--summary"),
+        ("attribution", "--cell-attribution"),
+    ];
+    for (name, flag) in unsupported.iter() {
+        if sub_m.is_present(name) {
+            return Err(format!("{} needs a chain of two modalities", flag).into());
+        }
+    }
+
+    let names = mm_obj.names();
+    let mut hops = Vec::new();
+    for (hop, links_path) in links_paths.into_iter().enumerate() {
+        let (sec, pivot) = (chain[hop], chain[hop + 1]);
+        if !mm_obj.is_paired(sec, pivot) {
+            return Err(format!(
+                "walking the chain needs paired cells, but {} and {} are unpaired",
+                names[sec], names[pivot]
+            )
+            .into());
+        }
+
+        info!("Creating Link object");
+        let mut links_obj = links::Links::new(mm_obj, links_path, sec, pivot, orientation, report)?;
+        info!("{:?}", links_obj);
+
+        if let Some(max_size) = sub_m.value_of("maxregion") {
+            let num_dropped = links_obj.limit_region_size(max_size.parse::<usize>()?);
+            info!(
+                "Dropped {} links of {} to limit the region size",
+                num_dropped,
+                links_obj.name()
+            );
+        }
+        hops.push(links_obj);
+    }
+
+    info!("Starting the walk over the chain");
+    let ofile = carina::file::bufwriter_from_clap(sub_m, "output")?;
+    walk::callback(mm_obj, &hops, ofile, config)
+}
@@ -0,0 +1,588 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{mpsc, Arc};
+
+use crossbeam::queue::ArrayQueue;
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use crate::alias;
+use crate::configs;
+use crate::gibbs;
+use crate::links;
+use crate::multimodal;
+
+// A region of a chain of links, i.e. a connected group of the features lying on
+// complete paths from the first modality of the chain up to the pivot. feats[k]
+// holds the features of the k-th modality and every path the positions of its
+// features within them, one per modality.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainRegion {
+    pub feats: Vec<Vec<usize>>,
+    pub paths: Vec<Vec<usize>>,
+}
+
+impl ChainRegion {
+    fn new(
+        hops: &[links::Links<f32>],
+        feats: Vec<Vec<usize>>,
+        max_paths: usize,
+    ) -> Result<ChainRegion, Box<dyn Error>> {
+        let lookups: Vec<HashMap<usize, usize>> = feats.iter().map(|x| positions(x)).collect();
+
+        // dense regions can have far too many paths to hold them all
+        let num_paths = count_paths(hops, &feats, &lookups);
+        if num_paths > max_paths {
+            return Err(format!(
+                "region of {} features has {} complete paths, more than --max-paths {}, lower --max-region-size to split it",
+                feats.iter().map(|x| x.len()).sum::<usize>(),
+                num_paths,
+                max_paths
+            )
+            .into());
+        }
+
+        // depth first from every feature of the first modality, only following
+        // the links to features of the region
+        let mut paths = Vec::new();
+        let mut stack: Vec<Vec<usize>> = (0..feats[0].len()).map(|x| vec![x]).collect();
+        while let Some(path) = stack.pop() {
+            let level = path.len() - 1;
+            if level == hops.len() {
+                paths.push(path);
+                continue;
+            }
+
+            let feat = feats[level][path[level]];
+            for next in hops[level].entry_to_pivot(feat) {
+                if let Some(&position) = lookups[level + 1].get(next) {
+                    let mut longer = path.clone();
+                    longer.push(position);
+                    stack.push(longer);
+                }
+            }
+        }
+        paths.sort();
+
+        Ok(ChainRegion { feats, paths })
+    }
+
+    // FNV-1a hash of the features from the pivot down, for a single link the
+    // same as the one of the pairwise region
+    fn hash(&self, prefix: &[u8], hops: &[links::Links<f32>]) -> u64 {
+        let groups: Vec<Vec<&str>> = (0..self.feats.len())
+            .rev()
+            .map(|level| {
+                self.feats[level]
+                    .iter()
+                    .map(|&x| feature_string(hops, level, x))
+                    .collect()
+            })
+            .collect();
+        gibbs::feature_hash(prefix, &groups)
+    }
+
+    pub fn id(&self, hops: &[links::Links<f32>]) -> String {
+        format!("{:016x}", self.hash(&[], hops))
+    }
+
+    fn write(
+        &self,
+        ofile: &mut BufWriter<File>,
+        hops: &[links::Links<f32>],
+        gamma: &gibbs::Gamma,
+    ) -> Result<(), Box<dyn Error>> {
+        let region_id = self.id(hops);
+        let total: u32 = gamma.stats().iter().sum();
+
+        for (index, val) in gamma.values().iter().enumerate() {
+            if *val == 0.0 {
+                continue;
+            }
+            for (level, &position) in self.paths[index].iter().enumerate() {
+                let feat = self.feats[level][position];
+                write!(ofile, "{}\t", feature_string(hops, level, feat))?;
+            }
+            writeln!(
+                ofile,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                val,
+                region_id,
+                gamma.rhat()[index],
+                gamma.ess()[index],
+                gamma.is_converged(),
+                gamma.stats()[index],
+                total,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// number of complete paths of a region, counting for every feature the paths up
+// to the pivot from the ones of the next modality
+fn count_paths(
+    hops: &[links::Links<f32>],
+    feats: &[Vec<usize>],
+    lookups: &[HashMap<usize, usize>],
+) -> usize {
+    let mut counts = vec![1_usize; feats[hops.len()].len()];
+    for level in (0..hops.len()).rev() {
+        counts = feats[level]
+            .iter()
+            .map(|&feat| {
+                hops[level]
+                    .entry_to_pivot(feat)
+                    .iter()
+                    .filter_map(|x| lookups[level + 1].get(x))
+                    .fold(0_usize, |acc, &position| {
+                        acc.saturating_add(counts[position])
+                    })
+            })
+            .collect();
+    }
+
+    counts.into_iter().fold(0_usize, usize::saturating_add)
+}
+
+fn positions(feats: &[usize]) -> HashMap<usize, usize> {
+    feats
+        .iter()
+        .enumerate()
+        .map(|(index, &feat)| (feat, index))
+        .collect()
+}
+
+// the k-th modality is the sec one of the k-th link, but for the pivot
+fn feature_string<'a>(hops: &'a [links::Links<f32>], level: usize, feat: usize) -> &'a str {
+    match level < hops.len() {
+        true => hops[level].feature_string(false, feat),
+        false => hops[level - 1].feature_string(true, feat),
+    }
+}
+
+// Features of every modality on some complete path of links, i.e. reachable
+// from the first modality of the chain and reaching the pivot.
+fn path_features(hops: &[links::Links<f32>]) -> Vec<HashSet<usize>> {
+    let secs: Vec<HashSet<usize>> = hops.iter().map(|x| x.get_sec_features()).collect();
+
+    let mut reached = vec![secs[0].clone()];
+    for (level, hop) in hops.iter().enumerate() {
+        let next: HashSet<usize> = reached[level]
+            .intersection(&secs[level])
+            .flat_map(|&x| hop.entry_to_pivot(x).iter().cloned())
+            .collect();
+        reached.push(next);
+    }
+
+    for (level, hop) in hops.iter().enumerate().rev() {
+        let (lower, upper) = reached.split_at_mut(level + 1);
+        lower[level].retain(|x| {
+            secs[level].contains(x) && hop.entry_to_pivot(*x).iter().any(|y| upper[0].contains(y))
+        });
+    }
+
+    reached
+}
+
+// connected components of the links between the features on complete paths,
+// ordered by their features, failing on regions of more than max_paths paths
+pub fn chain_regions(
+    hops: &[links::Links<f32>],
+    max_paths: usize,
+) -> Result<Vec<ChainRegion>, Box<dyn Error>> {
+    let mut nodes: Vec<(usize, usize)> = path_features(hops)
+        .into_iter()
+        .enumerate()
+        .flat_map(|(level, feats)| feats.into_iter().map(move |x| (level, x)))
+        .collect();
+    nodes.sort();
+    let node_index: HashMap<(usize, usize), usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(index, &node)| (node, index))
+        .collect();
+
+    let mut sets = links::DisjointSets::new(nodes.len());
+    for (index, &(level, feat)) in nodes.iter().enumerate() {
+        if level == hops.len() {
+            continue;
+        }
+        for &next in hops[level].entry_to_pivot(feat) {
+            if let Some(&other) = node_index.get(&(level + 1, next)) {
+                sets.union(index, other);
+            }
+        }
+    }
+
+    let mut components = HashMap::<usize, Vec<Vec<usize>>>::new();
+    for (index, &(level, feat)) in nodes.iter().enumerate() {
+        components
+            .entry(sets.find(index))
+            .or_insert_with(|| vec![Vec::new(); hops.len() + 1])[level]
+            .push(feat);
+    }
+
+    let mut groups: Vec<Vec<Vec<usize>>> = components.into_iter().map(|x| x.1).collect();
+    groups.sort();
+    groups
+        .into_iter()
+        .map(|feats| ChainRegion::new(hops, feats, max_paths))
+        .collect()
+}
+
+// Neighbourhoods of one link of the chain within a region, as the sec positions
+// per pivot position and the pivot positions per sec position, leaving out the
+// features off the complete paths.
+fn hop_neighbourhoods(
+    hop: &links::Links<f32>,
+    sec_feats: &[usize],
+    pivot_feats: &[usize],
+) -> (Vec<alias::Neighbourhood>, Vec<alias::Neighbourhood>) {
+    let (sec_lookup, pivot_lookup) = (positions(sec_feats), positions(pivot_feats));
+
+    let (sec_indices, sec_weights): (Vec<Vec<usize>>, Vec<Vec<f32>>) = pivot_feats
+        .iter()
+        .map(|&pivot| {
+            hop.entry_from_pivot(pivot)
+                .iter()
+                .filter_map(|sec| sec_lookup.get(sec).map(|&x| (x, hop.weight(*sec, pivot))))
+                .unzip()
+        })
+        .unzip();
+    let (pivot_indices, pivot_weights): (Vec<Vec<usize>>, Vec<Vec<f32>>) = sec_feats
+        .iter()
+        .map(|&sec| {
+            hop.entry_to_pivot(sec)
+                .iter()
+                .filter_map(|pivot| {
+                    pivot_lookup
+                        .get(pivot)
+                        .map(|&x| (x, hop.weight(sec, *pivot)))
+                })
+                .unzip()
+        })
+        .unzip();
+
+    (
+        alias::build_neighbourhoods(&sec_indices, &sec_weights),
+        alias::build_neighbourhoods(&pivot_indices, &pivot_weights),
+    )
+}
+
+// region specific view of the data shared by all the chains of a region
+struct PathSampler<'a> {
+    region: &'a ChainRegion,
+    cell_index: multimodal::CellIndex<'a>,
+    mats: Vec<multimodal::SubMatrix>,
+    down_hoods: Vec<Vec<alias::Neighbourhood>>,
+    up_hoods: Vec<Vec<alias::Neighbourhood>>,
+    path_index: HashMap<Vec<usize>, usize>,
+    cells_dist: Uniform<usize>,
+    paths_dist: Uniform<usize>,
+}
+
+impl<'a> PathSampler<'a> {
+    fn new(
+        region: &'a ChainRegion,
+        hops: &'a [links::Links<'a, f32>],
+        mm_obj: &'a multimodal::MultiModalExperiment<f32>,
+    ) -> PathSampler<'a> {
+        // paired modalities share their cells, so a row is the same cell in
+        // the matrices of all the modalities
+        let assays: Vec<usize> = hops
+            .iter()
+            .map(|x| x.sec_assay())
+            .chain(hops.last().map(|x| x.pivot_assay()))
+            .collect();
+        let cell_index = mm_obj.cell_index(assays[0], None);
+        let mats: Vec<multimodal::SubMatrix> = assays
+            .iter()
+            .zip(region.feats.iter())
+            .map(|(&assay, feats)| mm_obj.get_submatrix(&cell_index, feats, assay))
+            .collect();
+        let cells_dist = Uniform::from(0..mats[0].num_rows());
+
+        // down_hoods[k][p] draws a position of the k-th modality given the
+        // position p of the next one, up_hoods[k][p] a position of the next
+        // modality given the position p of the k-th one
+        let (down_hoods, up_hoods) = hops
+            .iter()
+            .enumerate()
+            .map(|(level, hop)| {
+                hop_neighbourhoods(hop, &region.feats[level], &region.feats[level + 1])
+            })
+            .unzip();
+        let path_index = region
+            .paths
+            .iter()
+            .enumerate()
+            .map(|(index, path)| (path.clone(), index))
+            .collect();
+
+        PathSampler {
+            region,
+            cell_index,
+            mats,
+            down_hoods,
+            up_hoods,
+            path_index,
+            cells_dist,
+            paths_dist: Uniform::from(0..region.paths.len()),
+        }
+    }
+}
+
+impl<'a> gibbs::Walk for PathSampler<'a> {
+    type State = Vec<usize>;
+
+    fn num_states(&self) -> usize {
+        self.region.paths.len()
+    }
+
+    fn init_state(&self, rng: &mut StdRng) -> Vec<usize> {
+        self.region.paths[self.paths_dist.sample(rng)].clone()
+    }
+
+    // Walks the links down from the pivot to the first modality and back up,
+    // every feature being drawn among the ones linked to the feature just
+    // drawn, by its expression in the sampled cell. A single link is the same
+    // walk as the one of the pairwise regions.
+    fn step(&self, state: &mut Vec<usize>, rng: &mut StdRng) -> Result<usize, Box<dyn Error>> {
+        // sample a cell, as a local row of the submatrices
+        let row = self.cells_dist.sample(rng);
+        let num_hops = self.down_hoods.len();
+
+        for level in (0..num_hops).rev() {
+            let coin_toss_value: f32 = rng.gen_range(0.0, 1.0);
+            state[level] = self.down_hoods[level][state[level + 1]].sample(
+                &self.mats[level],
+                row,
+                coin_toss_value,
+            );
+        }
+
+        for level in 0..num_hops {
+            let coin_toss_value: f32 = rng.gen_range(0.0, 1.0);
+            state[level + 1] = self.up_hoods[level][state[level]].sample(
+                &self.mats[level + 1],
+                row,
+                coin_toss_value,
+            );
+        }
+
+        Ok(self.cell_index.to_global(row))
+    }
+
+    fn state_index(&self, state: &Vec<usize>) -> usize {
+        self.path_index[state]
+    }
+}
+
+// gamma over the complete paths of a region, one row per path
+pub fn process_region(
+    region: &ChainRegion,
+    config: &configs::GibbsConfig,
+    hops: &[links::Links<f32>],
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+) -> Result<gibbs::Gamma, Box<dyn Error>> {
+    let sampler = PathSampler::new(region, hops, mm_obj);
+    let seed = region.hash(&config.seed.to_le_bytes(), hops);
+    gibbs::sample_region(&sampler, config, seed, region.paths.len(), 1)
+}
+
+pub fn write_header(
+    ofile: &mut BufWriter<File>,
+    config: &configs::GibbsConfig,
+    hops: &[links::Links<f32>],
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+) -> Result<(), Box<dyn Error>> {
+    let hop_names: Vec<String> = hops.iter().map(|x| x.name()).collect();
+    writeln!(ofile, "# links={}", hop_names.join(","))?;
+    gibbs::write_config(ofile, config)?;
+
+    let names = mm_obj.names();
+    for hop in hops.iter() {
+        write!(ofile, "{}\t", names[hop.sec_assay()])?;
+    }
+    writeln!(
+        ofile,
+        "{}\tgamma\tregion_id\trhat\tess\tconverged\tcount\ttotal_samples",
+        names[hops[hops.len() - 1].pivot_assay()]
+    )?;
+
+    Ok(())
+}
+
+// (region index, gamma) sent back by the workers
+type Processed = (usize, gibbs::Gamma);
+
+// Gamma over the complete paths of a chain of links, one column per modality
+// of the chain. Regions are processed largest first and written in order.
+pub fn callback(
+    mm_obj: &multimodal::MultiModalExperiment<f32>,
+    hops: &[links::Links<f32>],
+    mut ofile: BufWriter<File>,
+    config: &configs::GibbsConfig,
+) -> Result<(), Box<dyn Error>> {
+    info!("Finding the regions of the complete paths");
+    let regions = chain_regions(hops, config.max_paths)?;
+    info!(
+        "Found total {} regions with {} paths",
+        regions.len(),
+        regions.iter().map(|x| x.paths.len()).sum::<usize>()
+    );
+
+    write_header(&mut ofile, config, hops, mm_obj)?;
+    if regions.is_empty() {
+        warn!("No complete path of links through the chain");
+        return Ok(());
+    }
+
+    let num_regions = regions.len();
+    let pbar = ProgressBar::new(num_regions as u64);
+    pbar.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.red} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} {msg}",
+            )
+            .progress_chars("╢▌▌░╟"),
+    );
+
+    let q = Arc::new(ArrayQueue::<usize>::new(num_regions));
+    let mut schedule: Vec<usize> = (0..num_regions).collect();
+    schedule.sort_by_key(|&index| std::cmp::Reverse(regions[index].paths.len()));
+    for index in schedule {
+        q.push(index).unwrap();
+    }
+
+    let num_threads = config.num_threads;
+    let (tx, rx) = mpsc::sync_channel::<Processed>(num_threads);
+    let mut gammas: Vec<Option<gibbs::Gamma>> = (0..num_regions).map(|_| None).collect();
+    let mut next_index = 0;
+    let mut num_unconverged = 0;
+    crossbeam::scope(|scope| {
+        for _worker in 0..num_threads {
+            let tx = tx.clone();
+            let reader = Arc::clone(&q);
+            let regions = &regions;
+
+            scope.spawn(move |_| {
+                while let Some(index) = reader.pop() {
+                    let gamma = process_region(&regions[index], config, hops, mm_obj)
+                        .expect("can't process chain region");
+                    tx.send((index, gamma)).expect("Could not send mid data!");
+                }
+            });
+        }
+        drop(tx);
+
+        for (index, gamma) in rx.iter() {
+            pbar.inc(1);
+            num_unconverged += !gamma.is_converged() as usize;
+            gammas[index] = Some(gamma);
+
+            while let Some(gamma) = gammas.get_mut(next_index).and_then(|x| x.take()) {
+                regions[next_index]
+                    .write(&mut ofile, hops, &gamma)
+                    .expect("can't write gamma");
+                next_index += 1;
+            }
+        }
+    })
+    .unwrap(); //end crossbeam
+    assert_eq!(next_index, num_regions, "regions left unwritten");
+
+    if num_unconverged > 0 {
+        warn!(
+            "{} / {} regions have R-hat above {}",
+            num_unconverged, num_regions, config.rhat_threshold
+        );
+    }
+
+    pbar.finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configs::GibbsConfig;
+    use crate::exact;
+    use crate::fixtures;
+    use crate::walk;
+
+    #[test]
+    fn test_chain_regions() {
+        let mm_obj = fixtures::chain();
+        let hops = vec![
+            fixtures::links(&mm_obj, "test/olaps.tsv"),
+            fixtures::hop(&mm_obj, "test/genes_proteins.tsv", 1, 2),
+        ];
+
+        // AP006222.2 has no protein, leaving its peaks off the complete paths
+        let regions = walk::chain_regions(&hops, 5).unwrap();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(
+            regions[0].feats,
+            vec![vec![0, 6, 7], vec![0, 1, 3], vec![0, 1, 2]]
+        );
+        assert_eq!(
+            regions[0].paths,
+            vec![
+                vec![0, 0, 0],
+                vec![0, 1, 1],
+                vec![1, 2, 2],
+                vec![2, 1, 1],
+                vec![2, 2, 2]
+            ]
+        );
+        let err = walk::chain_regions(&hops, 4).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "region of 9 features has 5 complete paths, more than --max-paths 4, lower --max-region-size to split it"
+        );
+
+        // the first link on its own gives back the pairwise region
+        let first = vec![fixtures::links(&mm_obj, "test/olaps.tsv")];
+        let pairwise = walk::chain_regions(&first, 5).unwrap();
+        assert_eq!(pairwise.len(), 2);
+        assert_eq!(pairwise[0].feats, vec![vec![0, 6, 7], vec![0, 1, 3]]);
+        assert_eq!(pairwise[0].paths.len(), 5);
+    }
+
+    #[test]
+    fn test_walk() {
+        let mm_obj = fixtures::chain();
+        let hops = vec![
+            fixtures::links(&mm_obj, "test/olaps.tsv"),
+            fixtures::hop(&mm_obj, "test/genes_proteins.tsv", 1, 2),
+        ];
+        let regions = walk::chain_regions(&hops, 5).unwrap();
+
+        let config = GibbsConfig {
+            min_samples: 200_000,
+            max_samples: 200_000,
+            seed: 7,
+            ..GibbsConfig::default()
+        };
+        let gamma = walk::process_region(&regions[0], &config, &hops, &mm_obj).unwrap();
+        assert!(gamma.is_converged());
+        assert_eq!(gamma.values().len(), 5);
+
+        // every gene has its own protein, so the walk can't tell the proteins
+        // apart from their genes and the paths follow the peak gene links
+        let sec_feats = &regions[0].feats[0];
+        let pivot_feats = &regions[0].feats[1];
+        let exact =
+            exact::process_region(sec_feats, pivot_feats, &hops[0], &mm_obj, None, None).unwrap();
+        for (path, val) in regions[0].paths.iter().zip(gamma.values().iter()) {
+            let expected = exact.values()[path[0] * pivot_feats.len() + path[1]];
+            assert!((val - expected).abs() < 1e-2);
+        }
+    }
+}